@@ -1,22 +1,540 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+};
 
 use egui::{
-    collapsing_header::CollapsingState, Button, CentralPanel, Color32, Frame, Label, Layout,
-    RichText, Rounding, ScrollArea, Sense, TextEdit,
+    collapsing_header::CollapsingState, text::LayoutJob, Button, CentralPanel, Color32, Frame,
+    Label, Layout, RichText, Rounding, ScrollArea, Sense, TextEdit, TextFormat, TextStyle,
 };
 use poll_promise::Promise;
 use sequence_generator::sequence_generator;
+use wred_server::{
+    diff::{Diff, Hunk},
+    line::{Level, LogLine},
+    template::TemplateStat,
+    Outcome,
+};
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct WRedNetDbgApp {
     base_url: String,
     secret: String,
+    #[serde(default)]
+    show_timestamps: bool,
+    #[serde(default)]
+    min_level: Option<Level>,
+    #[serde(default)]
+    module_filter: String,
+    /// Only entries with a label containing this are listed.
+    #[serde(default)]
+    label_filter: String,
+    /// Highlight lines whose template no other entry has.
+    #[serde(default)]
+    show_new_lines: bool,
+    /// Entry whose panics are shown in the panic pane.
+    #[serde(skip)]
+    panic_pane: Option<u64>,
+    /// Entry picked as the old side of a diff.
+    #[serde(skip)]
+    diff_base: Option<u64>,
+    #[serde(skip)]
+    diff: Option<DiffView>,
     #[serde(skip)]
     pub log_cache: HashMap<u64, Promise<Result<wred_server::LogEntry, String>>>,
     #[serde(skip)]
+    /// Offsets of the lines of each entry whose template is new.
+    #[serde(skip)]
+    pub new_lines_cache: HashMap<u64, Promise<Result<HashSet<usize>, String>>>,
+    #[serde(skip)]
     pub log_cache_ents: Option<Promise<Result<Vec<wred_server::LogEntryPartial>, String>>>,
 }
 
+/// Two entries being compared.
+struct DiffView {
+    a: u64,
+    b: u64,
+    /// Fetched with the lines of both entries, which live entries may have
+    /// gained since they were cached.
+    diff: Promise<Result<Diff, String>>,
+    current: usize,
+    /// Whether to scroll to the current hunk.
+    scroll: bool,
+}
+
+impl DiffView {
+    fn new(ctx: &egui::Context, base_url: &str, a: u64, b: u64) -> Self {
+        let ctx = ctx.clone();
+        let (sender, diff) = Promise::new();
+        let request = ehttp::Request::get(format!("{base_url}/diff/{a}/{b}"));
+        ehttp::fetch(request, move |response| {
+            let diff =
+                response.and_then(|v| postcard::from_bytes(&v.bytes).map_err(|e| e.to_string()));
+            sender.send(diff);
+            ctx.request_repaint();
+        });
+        Self {
+            a,
+            b,
+            diff,
+            current: 0,
+            scroll: true,
+        }
+    }
+}
+
+struct Formats {
+    text: TextFormat,
+    binary: TextFormat,
+    marker: TextFormat,
+}
+
+impl Formats {
+    fn new(ui: &egui::Ui) -> Self {
+        let text = TextFormat {
+            font_id: TextStyle::Body.resolve(ui.style()),
+            color: ui.visuals().text_color(),
+            ..Default::default()
+        };
+        let monospace = TextFormat {
+            font_id: TextStyle::Monospace.resolve(ui.style()),
+            color: ui.visuals().weak_text_color(),
+            ..Default::default()
+        };
+        Self {
+            text,
+            binary: TextFormat {
+                color: Color32::from_rgb(0xE0, 0xA0, 0x40),
+                background: ui.visuals().faint_bg_color,
+                ..monospace.clone()
+            },
+            marker: monospace,
+        }
+    }
+}
+
+/// Appends log data, showing bytes that aren't valid UTF-8 as hex.
+fn append_data(job: &mut LayoutJob, data: &[u8], text: &TextFormat, binary: &TextFormat) {
+    for chunk in wred_server::chunks(data) {
+        match chunk {
+            wred_server::Chunk::Text(v) => job.append(v, 0.0, text.clone()),
+            wred_server::Chunk::Binary(v) => {
+                let hex: Vec<_> = v.iter().map(|b| format!("{b:02X}")).collect();
+                job.append(&hex.join(" "), 0.0, binary.clone());
+            }
+        }
+    }
+}
+
+fn level_color(ui: &egui::Ui, level: Level) -> Color32 {
+    match level {
+        Level::Debug => ui.visuals().weak_text_color(),
+        Level::Info => ui.visuals().text_color(),
+        Level::Warning => Color32::from_rgb(0xE0, 0xC0, 0x40),
+        Level::Error => Color32::from_rgb(0xF0, 0x50, 0x50),
+        Level::Panic => Color32::from_rgb(0xFF, 0x40, 0xC0),
+    }
+}
+
+/// Formats a duration in microseconds as `mm:ss.mmm`.
+fn elapsed(micros: u64) -> String {
+    let ms = micros / 1000;
+    format!("{:02}:{:02}.{:03}", ms / 60_000, ms / 1000 % 60, ms % 1000)
+}
+
+/// Formats a duration in microseconds roughly, e.g. `3h` or `2d`.
+fn rough_duration(micros: u64) -> String {
+    match micros / 1_000_000 {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+fn segment_marker(seg: &wred_server::Segment, start: u64, first: bool) -> String {
+    let mut marker = format!("\u{2500}\u{2500} {}", seg.addr);
+    if first {
+        let d = std::time::UNIX_EPOCH + std::time::Duration::from_micros(seg.connected);
+        let localtime = chrono::DateTime::<chrono::Local>::from(d);
+        let _e = write!(
+            marker,
+            " connected at {} ({})",
+            elapsed(seg.connected.saturating_sub(start)),
+            localtime.format("%H:%M:%S")
+        );
+        if let Some(v) = seg.disconnected {
+            let _e = write!(
+                marker,
+                ", disconnected at {}",
+                elapsed(v.saturating_sub(start))
+            );
+        }
+    }
+    marker + "\n"
+}
+
+/// Short summary of the extracted metadata, e.g. `Navi 14 / 13.0 22A380 / WRed 1.0.2`.
+fn metadata_summary(metadata: &BTreeMap<String, String>) -> Option<String> {
+    let get = |k: &str| metadata.get(k).map(String::as_str);
+    let macos = match (get("macos.version"), get("macos.build")) {
+        (Some(v), Some(b)) => Some(format!("{v} {b}")),
+        (v, b) => v.or(b).map(str::to_owned),
+    };
+    let parts = [
+        get("gpu")
+            .or_else(|| get("gpu.device_id"))
+            .map(str::to_owned),
+        macos,
+        get("wred.version").map(|v| format!("WRed {v}")),
+    ];
+    let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+    (!parts.is_empty()).then(|| parts.join(" / "))
+}
+
+/// How entries are shown.
+struct View<'a> {
+    /// Show when each line arrived.
+    timestamps: bool,
+    min_level: Option<Level>,
+    module: &'a str,
+}
+
+impl View<'_> {
+    fn shows(&self, line: &LogLine) -> bool {
+        self.min_level.is_none_or(|v| line.level >= v)
+            && (self.module.is_empty()
+                || line
+                    .module
+                    .as_ref()
+                    .is_some_and(|v| v.eq_ignore_ascii_case(self.module)))
+    }
+}
+
+/// Lays out an entry's data coloured by level, marking where each connection
+/// started if there were several and highlighting the lines at `new_lines`.
+fn entry_layout(
+    ui: &egui::Ui,
+    ent: &wred_server::LogEntry,
+    view: &View<'_>,
+    new_lines: Option<&HashSet<usize>>,
+) -> LayoutJob {
+    let formats = Formats::new(ui);
+    let end = ent.data.trim_ascii_end().len();
+    let lines = LogLine::parse_all(&ent.data);
+    let mut arrivals: Vec<_> = ent
+        .arrivals()
+        .into_iter()
+        .map(|(i, v)| (Some(i), v))
+        .collect();
+    if arrivals.is_empty() {
+        arrivals.push((
+            None,
+            wred_server::Arrival {
+                time: 0,
+                offset: 0,
+                len: ent.data.len(),
+            },
+        ));
+    }
+
+    let start = ent.segments.iter().map(|v| v.connected).min().unwrap_or(0);
+    let mut job = LayoutJob::default();
+    let mut seen = vec![false; ent.segments.len()];
+    let mut current = None;
+    let mut marker = None;
+    for (segment, arrival) in arrivals {
+        if arrival.offset >= end {
+            break;
+        }
+        if let Some(i) = segment.filter(|&i| ent.segments.len() > 1 && current != Some(i)) {
+            marker = Some(segment_marker(&ent.segments[i], start, !seen[i]));
+            seen[i] = true;
+            current = Some(i);
+        }
+
+        let mut pos = arrival.offset;
+        let data = &ent.data[pos..end.min(pos + arrival.len)];
+        for part in data.split_inclusive(|&b| b == b'\n') {
+            let line = &lines[lines.partition_point(|v| v.offset + v.len <= pos)];
+            let is_line_start = pos == line.offset;
+            pos += part.len();
+            if !view.shows(line) {
+                continue;
+            }
+            if let Some(marker) = marker.take() {
+                if !job.text.is_empty() && !job.text.ends_with('\n') {
+                    job.append("\n", 0.0, formats.text.clone());
+                }
+                job.append(&marker, 0.0, formats.marker.clone());
+            }
+            if view.timestamps && segment.is_some() && is_line_start {
+                let stamp = format!("[{}] ", elapsed(arrival.time.saturating_sub(start)));
+                job.append(&stamp, 0.0, formats.marker.clone());
+            }
+            let text = TextFormat {
+                color: level_color(ui, line.level),
+                background: if new_lines.is_some_and(|v| v.contains(&line.offset)) {
+                    Color32::from_rgb(0x30, 0x30, 0x60)
+                } else {
+                    Color32::TRANSPARENT
+                },
+                ..formats.text.clone()
+            };
+            append_data(&mut job, part, &text, &formats.binary);
+        }
+    }
+    job
+}
+
+fn outcome_color(ui: &egui::Ui, outcome: Outcome) -> Color32 {
+    match outcome {
+        Outcome::Unknown => ui.visuals().weak_text_color(),
+        Outcome::Success => Color32::from_rgb(0x40, 0xC0, 0x40),
+        Outcome::Panic => level_color(ui, Level::Panic),
+        Outcome::Hang => Color32::from_rgb(0xFF, 0xA0, 0x20),
+        Outcome::Aborted => Color32::from_rgb(0xFF, 0x60, 0x40),
+    }
+}
+
+/// Fetches the offsets of the lines of the entry `id` whose template is new.
+fn fetch_new_lines(
+    ctx: &egui::Context,
+    base_url: &str,
+    id: u64,
+) -> Promise<Result<HashSet<usize>, String>> {
+    let ctx = ctx.clone();
+    let (sender, promise) = Promise::new();
+    let request = ehttp::Request::get(format!("{base_url}/{id}/templates"));
+    ehttp::fetch(request, move |response| {
+        let offsets = response.and_then(|v| {
+            postcard::from_bytes::<Vec<TemplateStat>>(&v.bytes)
+                .map(|v| {
+                    v.into_iter()
+                        .filter(TemplateStat::is_new)
+                        .flat_map(|v| v.offsets)
+                        .collect()
+                })
+                .map_err(|e| e.to_string())
+        });
+        sender.send(offsets);
+        ctx.request_repaint();
+    });
+    promise
+}
+
+/// Unchanged lines shown around each hunk.
+const DIFF_CONTEXT: usize = 3;
+
+enum DiffRow {
+    Equal(usize, usize),
+    Changed(Option<usize>, Option<usize>),
+    /// Unchanged lines that aren't shown.
+    Folded(usize),
+}
+
+/// Adds rows for `len` unchanged lines from `x` and `y`, keeping context next
+/// to the hunks before and after them.
+fn push_equal(
+    rows: &mut Vec<DiffRow>,
+    (x, y): (usize, usize),
+    len: usize,
+    before: bool,
+    after: bool,
+) {
+    let head = if before { DIFF_CONTEXT.min(len) } else { 0 };
+    let tail = if after {
+        DIFF_CONTEXT.min(len - head)
+    } else {
+        0
+    };
+    rows.extend((0..head).map(|i| DiffRow::Equal(x + i, y + i)));
+    if len > head + tail {
+        rows.push(DiffRow::Folded(len - head - tail));
+    }
+    rows.extend((len - tail..len).map(|i| DiffRow::Equal(x + i, y + i)));
+}
+
+/// Pairs up the lines of two entries, returning the rows and the row each hunk
+/// starts at.
+fn diff_rows(hunks: &[Hunk], a_len: usize) -> (Vec<DiffRow>, Vec<usize>) {
+    let mut rows = Vec::new();
+    let mut starts = Vec::with_capacity(hunks.len());
+    let (mut x, mut y) = (0, 0);
+    for (i, hunk) in hunks.iter().enumerate() {
+        push_equal(&mut rows, (x, y), hunk.a.start - x, i > 0, true);
+        starts.push(rows.len());
+        let (a_len, b_len) = (hunk.a.len(), hunk.b.len());
+        rows.extend((0..a_len.max(b_len)).map(|i| {
+            DiffRow::Changed(
+                (i < a_len).then_some(hunk.a.start + i),
+                (i < b_len).then_some(hunk.b.start + i),
+            )
+        }));
+        x = hunk.a.end;
+        y = hunk.b.end;
+    }
+    push_equal(
+        &mut rows,
+        (x, y),
+        a_len.saturating_sub(x),
+        !hunks.is_empty(),
+        false,
+    );
+    (rows, starts)
+}
+
+/// Shows two entries side by side with the hunks between them highlighted.
+fn diff_pane(ui: &mut egui::Ui, diff: &Diff, current: &mut usize, scroll: &mut bool) {
+    let hunks = &diff.hunks;
+    ui.horizontal(|ui| {
+        if ui
+            .add_enabled(*current > 0, Button::new("\u{2B06} Previous"))
+            .clicked()
+        {
+            *current -= 1;
+            *scroll = true;
+        }
+        if ui
+            .add_enabled(*current + 1 < hunks.len(), Button::new("\u{2B07} Next"))
+            .clicked()
+        {
+            *current += 1;
+            *scroll = true;
+        }
+        if hunks.is_empty() {
+            ui.label("No differences");
+        } else {
+            ui.label(format!("Hunk {} of {}", *current + 1, hunks.len()));
+        }
+    });
+    ui.separator();
+
+    let (rows, starts) = diff_rows(hunks, diff.a.len());
+    let text = |lines: &[String], i: usize| lines.get(i).map_or("", |v| v.trim_end()).to_owned();
+    let removed = Color32::from_rgb(0xF0, 0x50, 0x50);
+    let added = Color32::from_rgb(0x40, 0xC0, 0x40);
+    ScrollArea::both()
+        .auto_shrink([false, false])
+        .show(ui, |ui| {
+            egui::Grid::new("diff_rows").num_columns(2).show(ui, |ui| {
+                for (i, row) in rows.iter().enumerate() {
+                    let resp = match *row {
+                        DiffRow::Equal(x, y) => {
+                            ui.label(RichText::new(text(&diff.a, x)).monospace().weak());
+                            ui.label(RichText::new(text(&diff.b, y)).monospace().weak())
+                        }
+                        DiffRow::Changed(x, y) => {
+                            let line = |lines: &[String], i: Option<usize>, color| {
+                                RichText::new(i.map(|i| text(lines, i)).unwrap_or_default())
+                                    .monospace()
+                                    .color(color)
+                            };
+                            ui.label(line(&diff.a, x, removed));
+                            ui.label(line(&diff.b, y, added))
+                        }
+                        DiffRow::Folded(n) => {
+                            let resp = ui.label(
+                                RichText::new(format!("\u{22EF} {n} unchanged lines")).weak(),
+                            );
+                            ui.label("");
+                            resp
+                        }
+                    };
+                    if *scroll && starts.get(*current) == Some(&i) {
+                        resp.scroll_to_me(Some(egui::Align::Center));
+                        *scroll = false;
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+}
+
+/// Shows the panics of an entry, returning whether the pane should be closed.
+fn panic_pane(ui: &mut egui::Ui, ent: &wred_server::LogEntry) -> bool {
+    let mut close = false;
+    ui.horizontal(|ui| {
+        ui.heading("Kernel panic");
+        ui.with_layout(Layout::right_to_left(egui::Align::Center), |ui| {
+            close = ui
+                .add(Button::new(RichText::new("\u{274C}").heading()).frame(false))
+                .clicked();
+        });
+    });
+    ScrollArea::vertical().show(ui, |ui| {
+        for (i, report) in ent.panics.iter().enumerate() {
+            ui.separator();
+            ui.horizontal(|ui| {
+                if let Some(cpu) = report.cpu {
+                    ui.label(RichText::new(format!("CPU {cpu}")).strong());
+                }
+                if let Some(caller) = report.caller {
+                    ui.label(RichText::new(format!("caller {caller:#x}")).monospace());
+                }
+                if let Some(process) = &report.process {
+                    ui.label(process);
+                }
+                if !report.is_complete {
+                    ui.label(RichText::new("incomplete").weak())
+                        .on_hover_text("The end of the panic wasn't received (yet)");
+                }
+                if ui
+                    .add(Button::new("\u{1F5D0}").frame(false))
+                    .on_hover_text("Copy panic text")
+                    .clicked()
+                {
+                    let end = (report.offset + report.len).min(ent.data.len());
+                    ui.output().copied_text =
+                        String::from_utf8_lossy(&ent.data[report.offset.min(end)..end])
+                            .into_owned();
+                }
+            });
+            ui.label(RichText::new(&report.message).color(level_color(ui, Level::Panic)));
+            if !report.backtrace.is_empty() {
+                ui.label(RichText::new("Backtrace").strong());
+                egui::Grid::new(("panic_backtrace", i))
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for frame in &report.backtrace {
+                            ui.label(RichText::new(format!("{:#018x}", frame.frame)).monospace());
+                            ui.label(
+                                RichText::new(format!("{:#018x}", frame.return_address))
+                                    .monospace(),
+                            );
+                            let resp = ui.label(
+                                frame
+                                    .resolved
+                                    .as_deref()
+                                    .or(frame.symbol.as_deref())
+                                    .unwrap_or_default(),
+                            );
+                            if let (Some(_), Some(symbol)) = (&frame.resolved, &frame.symbol) {
+                                resp.on_hover_text(symbol);
+                            }
+                            ui.end_row();
+                        }
+                    });
+            }
+            if !report.kexts.is_empty() {
+                ui.label(RichText::new("Kernel extensions").strong());
+                for kext in &report.kexts {
+                    let text = RichText::new(format!("{} {}", kext.name, kext.version));
+                    let resp = ui.label(if kext.is_dependency {
+                        text.weak()
+                    } else {
+                        text
+                    });
+                    if let (Some(start), Some(end)) = (kext.start, kext.end) {
+                        resp.on_hover_text(format!("{start:#x}-{end:#x}"));
+                    }
+                }
+            }
+        }
+    });
+    close
+}
+
 impl Default for WRedNetDbgApp {
     fn default() -> Self {
         #[cfg(target_arch = "wasm32")]
@@ -33,7 +551,16 @@ impl Default for WRedNetDbgApp {
         Self {
             base_url,
             secret: String::new(),
+            show_timestamps: false,
+            min_level: None,
+            module_filter: String::new(),
+            label_filter: String::new(),
+            show_new_lines: false,
+            panic_pane: None,
+            diff_base: None,
+            diff: None,
             log_cache: HashMap::default(),
+            new_lines_cache: HashMap::default(),
             log_cache_ents: None,
         }
     }
@@ -91,9 +618,38 @@ impl eframe::App for WRedNetDbgApp {
                             .clicked()
                         {
                             self.log_cache.clear();
+                            self.new_lines_cache.clear();
                             self.log_cache_ents = None;
                         }
                         ui.separator();
+                        ui.checkbox(&mut self.show_timestamps, "Timestamps");
+                        ui.checkbox(&mut self.show_new_lines, "New lines")
+                            .on_hover_text("Highlight lines no other log has");
+                        ui.add(
+                            TextEdit::singleline(&mut self.label_filter)
+                                .desired_width(80.0)
+                                .hint_text("Label"),
+                        );
+                        ui.add(
+                            TextEdit::singleline(&mut self.module_filter)
+                                .desired_width(80.0)
+                                .hint_text("Module"),
+                        );
+                        egui::ComboBox::from_id_source("min_level")
+                            .selected_text(
+                                self.min_level
+                                    .map_or_else(|| "All".to_owned(), |v| v.to_string()),
+                            )
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.min_level, None, "All");
+                                for level in Level::ALL {
+                                    ui.selectable_value(
+                                        &mut self.min_level,
+                                        Some(level),
+                                        level.to_string(),
+                                    );
+                                }
+                            });
                         ui.add(
                             TextEdit::singleline(&mut self.secret)
                                 .password(true)
@@ -117,6 +673,60 @@ impl eframe::App for WRedNetDbgApp {
             promise
         });
 
+        if let Some(view) = &mut self.diff {
+            let mut open = true;
+            // Borrowed apart, so the diff is shown without copying it.
+            let DiffView {
+                a,
+                b,
+                diff,
+                current,
+                scroll,
+            } = view;
+            egui::Window::new(format!("Diff {a} \u{2192} {b}"))
+                .id(egui::Id::new("diff"))
+                .open(&mut open)
+                .default_size([960.0, 640.0])
+                .show(ctx, |ui| match diff.ready() {
+                    Some(Err(e)) => {
+                        ui.colored_label(Color32::RED, e);
+                    }
+                    Some(Ok(diff)) if diff.is_consistent() => {
+                        diff_pane(ui, diff, current, scroll);
+                    }
+                    Some(Ok(_)) => {
+                        ui.colored_label(
+                            Color32::RED,
+                            "Received a diff that doesn't fit its lines",
+                        );
+                    }
+                    None => {
+                        ui.spinner();
+                    }
+                });
+            if !open {
+                self.diff = None;
+            }
+        }
+
+        if let Some(id) = self.panic_pane {
+            if let Some(Ok(ent)) = self.log_cache.get(&id).and_then(Promise::ready) {
+                egui::SidePanel::right("panic_pane")
+                    .resizable(true)
+                    .default_width(420.0)
+                    .show(ctx, |ui| {
+                        if panic_pane(ui, ent) {
+                            self.panic_pane = None;
+                        }
+                    });
+            }
+        }
+
+        let view = View {
+            timestamps: self.show_timestamps,
+            min_level: self.min_level,
+            module: self.module_filter.trim(),
+        };
         CentralPanel::default().show(ctx, |ui| {
             ScrollArea::vertical().show(ui, |ui| match cached_promise.ready() {
                 None => {
@@ -128,7 +738,16 @@ impl eframe::App for WRedNetDbgApp {
                 Some(Ok(ents)) => {
                     ui.set_width(ui.available_width());
 
+                    let label_filter = self.label_filter.trim().to_lowercase();
                     for ent in ents {
+                        if !label_filter.is_empty()
+                            && !ent
+                                .labels
+                                .keys()
+                                .any(|v| v.to_lowercase().contains(&label_filter))
+                        {
+                            continue;
+                        }
                         let cached_promise = self.log_cache.entry(ent.id).or_insert_with(|| {
                             let ctx = ctx.clone();
                             let (sender, promise) = Promise::new();
@@ -154,6 +773,11 @@ impl eframe::App for WRedNetDbgApp {
                                 )
                                 .show_header(ui, |ui| {
                                     ui.horizontal(|ui| {
+                                        ui.label(
+                                            RichText::new("\u{25CF}")
+                                                .color(outcome_color(ui, ent.outcome)),
+                                        )
+                                        .on_hover_text(ent.outcome.to_string());
                                         ui.add(
                                             Label::new(
                                                 RichText::new(ent.addr.to_string()).strong(),
@@ -168,6 +792,87 @@ impl eframe::App for WRedNetDbgApp {
                                                 }
                                             },
                                         );
+                                        if let Some(device) = &ent.device {
+                                            ui.label(RichText::new(device).strong());
+                                        }
+                                        if let Some(device_id) = &ent.device_id {
+                                            ui.label(RichText::new(device_id).monospace());
+                                        }
+                                        if let Some(summary) = metadata_summary(&ent.metadata) {
+                                            ui.label(RichText::new(summary).weak()).on_hover_ui(
+                                                |ui| {
+                                                    for (k, v) in &ent.metadata {
+                                                        ui.label(format!("{k}: {v}"));
+                                                    }
+                                                },
+                                            );
+                                        }
+                                        for (label, &severity) in &ent.labels {
+                                            if ui
+                                                .add(
+                                                    Button::new(
+                                                        RichText::new(label)
+                                                            .small()
+                                                            .color(level_color(ui, severity)),
+                                                    )
+                                                    .small(),
+                                                )
+                                                .on_hover_text(format!(
+                                                    "{severity}, click to filter"
+                                                ))
+                                                .clicked()
+                                            {
+                                                self.label_filter = label.clone();
+                                            }
+                                        }
+                                        let is_base = self.diff_base == Some(ent.id);
+                                        let resp = ui
+                                            .selectable_label(is_base, "\u{2194}")
+                                            .on_hover_text(if is_base {
+                                                "Picked for comparison, click to unpick"
+                                            } else if self.diff_base.is_some() {
+                                                "Compare with the picked log"
+                                            } else {
+                                                "Pick for comparison"
+                                            });
+                                        if resp.clicked() {
+                                            match self.diff_base.take() {
+                                                Some(base) if base != ent.id => {
+                                                    self.diff = Some(DiffView::new(
+                                                        ctx,
+                                                        &self.base_url,
+                                                        base,
+                                                        ent.id,
+                                                    ));
+                                                }
+                                                Some(_) => {}
+                                                None => self.diff_base = Some(ent.id),
+                                            }
+                                        }
+                                        if ent.has_panic
+                                            && ui
+                                                .add(
+                                                    Button::new(
+                                                        RichText::new("PANIC")
+                                                            .strong()
+                                                            .color(Color32::WHITE),
+                                                    )
+                                                    .fill(level_color(ui, Level::Panic)),
+                                                )
+                                                .on_hover_text("Show kernel panic")
+                                                .clicked()
+                                        {
+                                            self.panic_pane = Some(ent.id);
+                                        }
+                                        if ent.segments > 1 {
+                                            ui.label(
+                                                RichText::new(format!(
+                                                    "{} connections",
+                                                    ent.segments
+                                                ))
+                                                .weak(),
+                                            );
+                                        }
                                         let props = wred_server::get_id_props();
                                         let ms = sequence_generator::decode_id_unix_epoch_micros(
                                             ent.id, &props,
@@ -191,6 +896,22 @@ impl eframe::App for WRedNetDbgApp {
                                             RichText::new(fmter.convert_chrono(localtime, now))
                                                 .weak(),
                                         );
+                                        if let Some(expires) = ent.expires {
+                                            let left = u64::try_from(now.timestamp_millis())
+                                                .map_or(0, |v| expires.saturating_sub(v * 1000));
+                                            ui.separator();
+                                            ui.label(
+                                                RichText::new(format!(
+                                                    "expires in {}",
+                                                    rough_duration(left)
+                                                ))
+                                                .weak(),
+                                            )
+                                            .on_hover_text(
+                                                "Unsaved logs are evicted this long after their \
+                                                 last update",
+                                            );
+                                        }
                                     });
                                     ui.with_layout(
                                         Layout::right_to_left(egui::Align::Center),
@@ -217,7 +938,10 @@ impl eframe::App for WRedNetDbgApp {
                                                     .on_hover_text("Save to file")
                                                     .clicked()
                                                 {
-                                                    ui.output().copied_text = ent_full.data.clone();
+                                                    ui.output().open_url(format!(
+                                                        "{}/{}/raw",
+                                                        self.base_url, ent.id
+                                                    ));
                                                 }
                                                 if ui
                                                     .add(
@@ -229,7 +953,8 @@ impl eframe::App for WRedNetDbgApp {
                                                     .on_hover_text("\u{1F5D0} Copy text")
                                                     .clicked()
                                                 {
-                                                    ui.output().copied_text = ent_full.data.clone();
+                                                    ui.output().copied_text =
+                                                        ent_full.text().into_owned();
                                                 }
                                                 let resp = ui
                                                     .add_enabled(
@@ -342,6 +1067,7 @@ impl eframe::App for WRedNetDbgApp {
                                 })
                                 .body(|ui| {
                                     ui.set_width(ui.available_width());
+                                    let ent_id = ent.id;
 
                                     match cached_promise.ready() {
                                         None => {
@@ -356,7 +1082,18 @@ impl eframe::App for WRedNetDbgApp {
                                             });
                                         }
                                         Some(Ok(ent)) => {
-                                            ui.label(ent.data.trim());
+                                            let new_lines = if self.show_new_lines {
+                                                self.new_lines_cache
+                                                    .entry(ent_id)
+                                                    .or_insert_with(|| {
+                                                        fetch_new_lines(ctx, &self.base_url, ent_id)
+                                                    })
+                                                    .ready()
+                                                    .and_then(|v| v.as_ref().ok())
+                                            } else {
+                                                None
+                                            };
+                                            ui.label(entry_layout(ui, ent, &view, new_lines));
                                         }
                                     }
                                 })
@@ -374,3 +1111,100 @@ impl eframe::App for WRedNetDbgApp {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the rows show every line of both entries once and in order,
+    /// with the lines of each hunk as changed rows from where it starts.
+    fn check_rows(a: &[u8], b: &[u8]) {
+        let diff = Diff::new(a, b);
+        let (rows, starts) = diff_rows(&diff.hunks, diff.a.len());
+        let (mut x, mut y) = (0, 0);
+        for row in &rows {
+            match *row {
+                DiffRow::Equal(i, j) => {
+                    assert_eq!((i, j), (x, y));
+                    x += 1;
+                    y += 1;
+                }
+                DiffRow::Changed(i, j) => {
+                    assert!(i.is_some() || j.is_some());
+                    if let Some(i) = i {
+                        assert_eq!(i, x);
+                        x += 1;
+                    }
+                    if let Some(j) = j {
+                        assert_eq!(j, y);
+                        y += 1;
+                    }
+                }
+                DiffRow::Folded(n) => {
+                    x += n;
+                    y += n;
+                }
+            }
+        }
+        assert_eq!((x, y), (diff.a.len(), diff.b.len()));
+
+        assert_eq!(starts.len(), diff.hunks.len());
+        let changed = rows
+            .iter()
+            .filter(|v| matches!(v, DiffRow::Changed(..)))
+            .count();
+        let mut hunk_rows = 0;
+        for (hunk, &start) in diff.hunks.iter().zip(&starts) {
+            let len = hunk.a.len().max(hunk.b.len());
+            assert!(matches!(
+                rows[start],
+                DiffRow::Changed(i, j) if i == hunk.a.clone().next() || j == hunk.b.clone().next()
+            ));
+            assert!(rows[start..start + len]
+                .iter()
+                .all(|v| matches!(v, DiffRow::Changed(..))));
+            hunk_rows += len;
+        }
+        assert_eq!(changed, hunk_rows);
+    }
+
+    fn numbered(prefix: &str, range: std::ops::Range<usize>) -> Vec<u8> {
+        range
+            .flat_map(|i| format!("{prefix}{i}\n").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn rows_agree_with_hunks() {
+        check_rows(b"", b"");
+        check_rows(b"", b"a\nb\n");
+        check_rows(b"a\nb\n", b"");
+        check_rows(b"a\nb\n", b"a\nb\n");
+        check_rows(b"a\nb\n", b"c\nd\ne\n");
+        check_rows(&numbered("line ", 0..50), &numbered("line ", 0..50));
+
+        // Hunks at both ends and in the middle, with lines folded between.
+        let a = [numbered("old ", 0..2), numbered("line ", 0..40)].concat();
+        let b = [
+            numbered("line ", 0..20),
+            numbered("new ", 0..3),
+            numbered("line ", 20..40),
+            numbered("new ", 3..4),
+        ]
+        .concat();
+        check_rows(&a, &b);
+    }
+
+    #[test]
+    fn folds_unchanged_lines_past_the_context() {
+        let a = numbered("line ", 0..20);
+        let b = [numbered("line ", 0..10), numbered("line ", 11..20)].concat();
+        let diff = Diff::new(&a, &b);
+        let (rows, starts) = diff_rows(&diff.hunks, diff.a.len());
+        // Folded, context, the removed line, context, folded.
+        assert_eq!(rows.len(), 1 + DIFF_CONTEXT + 1 + DIFF_CONTEXT + 1);
+        assert_eq!(starts, [1 + DIFF_CONTEXT]);
+        assert!(matches!(rows[0], DiffRow::Folded(n) if n == 10 - DIFF_CONTEXT));
+        assert!(matches!(rows[starts[0]], DiffRow::Changed(Some(10), None)));
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+/// CIDR rules for a listener.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessList {
+    /// Networks allowed to connect. Everyone is allowed if empty.
+    pub allow: Vec<IpNet>,
+    /// Networks refused even if they are allowed.
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        (self.allow.is_empty() || self.allow.iter().any(|v| v.contains(&ip)))
+            && !self.deny.iter().any(|v| v.contains(&ip))
+    }
+}
+
+/// How often refusals of the same IP are logged, as each datagram is refused
+/// on its own.
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// IPs remembered before those logged longer ago than [`REPORT_INTERVAL`]
+/// are forgotten.
+const MAX_REPORTED: usize = 1024;
+
+/// Counts of connections refused by the access lists.
+#[derive(Debug, Default)]
+pub struct Rejections {
+    pub logger: AtomicU64,
+    pub api: AtomicU64,
+    /// When refusals were last logged, per IP and whether for the API.
+    reported: Mutex<HashMap<(IpAddr, bool), Instant>>,
+}
+
+impl Rejections {
+    /// Whether a refusal of `ip` is due to be logged, which it is once per
+    /// [`REPORT_INTERVAL`].
+    fn should_report(&self, ip: IpAddr, is_api: bool) -> bool {
+        let now = Instant::now();
+        let mut reported = self.reported.lock().unwrap();
+        let is_recent = |v: &Instant| now.duration_since(*v) < REPORT_INTERVAL;
+        if reported.get(&(ip, is_api)).is_some_and(is_recent) {
+            return false;
+        }
+        if reported.len() >= MAX_REPORTED {
+            reported.retain(|_, v| is_recent(v));
+        }
+        reported.insert((ip, is_api), now);
+        true
+    }
+
+    /// Checks `ip` against `list`, counting it if refused and logging it once
+    /// in a while.
+    pub fn check(&self, list: &AccessList, ip: IpAddr, is_api: bool) -> bool {
+        if list.permits(ip) {
+            return true;
+        }
+        let (name, count) = if is_api {
+            ("API", &self.api)
+        } else {
+            ("logger", &self.logger)
+        };
+        let n = count.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.should_report(ip, is_api) {
+            println!("Rejected {name} connection from {ip} ({n} so far)");
+        }
+        false
+    }
+}
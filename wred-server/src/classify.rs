@@ -0,0 +1,148 @@
+//! Classifies how each boot ended from marker lines and silence.
+//!
+//! An entry starts out `Unknown` and moves on as markers arrive. A panic
+//! overrides everything, and a kext bailing out sticks even if the desktop is
+//! reached afterwards, as the boot then went on without it.
+
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use wred_server::Outcome;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutcomeRules {
+    /// Regexes of lines showing the boot reached the desktop.
+    pub success: Vec<String>,
+    /// Regexes of lines showing a kext bailed out early.
+    pub aborted: Vec<String>,
+    /// Seconds without data after which an unfinished boot counts as hung.
+    pub hang_secs: u64,
+}
+
+impl Default for OutcomeRules {
+    fn default() -> Self {
+        Self {
+            success: vec![
+                r"(?i)\bboot(?:ing)? (?:complete|finished)\b".to_owned(),
+                r"\bloginwindow\b.*\b(?:Login Window Started|USER_PROCESS)\b".to_owned(),
+            ],
+            aborted: vec![
+                r"(?i)\b(?:bailing out|aborting|giving up)\b".to_owned(),
+                r"(?i)\bunsupported (?:gpu|device|platform|os)\b".to_owned(),
+            ],
+            hang_secs: 5 * 60,
+        }
+    }
+}
+
+/// What a line or the entry shows about the boot.
+#[derive(Debug, Clone, Copy)]
+enum Event {
+    Success,
+    Aborted,
+    Panic,
+}
+
+const fn next(state: Outcome, event: Event) -> Outcome {
+    match (state, event) {
+        (_, Event::Panic) | (Outcome::Panic, _) => Outcome::Panic,
+        (_, Event::Aborted) | (Outcome::Aborted, _) => Outcome::Aborted,
+        (_, Event::Success) => Outcome::Success,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Classifier {
+    success: RegexSet,
+    aborted: RegexSet,
+    hang_micros: u64,
+}
+
+impl Classifier {
+    pub fn new(rules: &OutcomeRules) -> Result<Self, regex::Error> {
+        Ok(Self {
+            success: RegexSet::new(&rules.success)?,
+            aborted: RegexSet::new(&rules.aborted)?,
+            hang_micros: rules.hang_secs.saturating_mul(1_000_000),
+        })
+    }
+
+    /// Advances `ent.outcome` for the complete lines of `ent.data`, starting
+    /// with the line `from` is in, and its panics.
+    pub fn apply(&self, ent: &mut wred_server::LogEntry, from: usize) {
+        if ent.outcome == Outcome::Panic {
+            return;
+        }
+        let lines = wred_server::line::complete_lines(&ent.data, from);
+        let text = String::from_utf8_lossy(&ent.data[lines]);
+        for line in text.lines() {
+            if self.aborted.is_match(line) {
+                ent.outcome = next(ent.outcome, Event::Aborted);
+            } else if self.success.is_match(line) {
+                ent.outcome = next(ent.outcome, Event::Success);
+            }
+        }
+        if !ent.panics.is_empty() {
+            ent.outcome = next(ent.outcome, Event::Panic);
+        }
+    }
+
+    /// Classifies `ent` from scratch, e.g. after the rules changed.
+    pub fn classify(&self, ent: &mut wred_server::LogEntry) {
+        ent.outcome = Outcome::Unknown;
+        self.apply(ent, 0);
+    }
+
+    /// The outcome at `now` of an entry with the marker outcome `outcome`,
+    /// telling apart boots that went silent.
+    pub const fn outcome(
+        &self,
+        outcome: Outcome,
+        is_ended: bool,
+        last_updated: u64,
+        now: u64,
+    ) -> Outcome {
+        match outcome {
+            Outcome::Unknown
+                if !is_ended && now.saturating_sub(last_updated) >= self.hang_micros =>
+            {
+                Outcome::Hang
+            }
+            v => v,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::entry;
+
+    #[test]
+    fn quiet_legacy_entries_hang() {
+        let classifier = Classifier::new(&OutcomeRules::default()).unwrap();
+        let hang = OutcomeRules::default().hang_secs * 1_000_000;
+        let mut ent = entry(b"IOKit: starting\n");
+        ent.is_closed = true;
+        classifier.classify(&mut ent);
+        let outcome = |ent: &wred_server::LogEntry, now| {
+            classifier.outcome(ent.outcome, ent.is_ended, ent.last_updated, now)
+        };
+        assert_eq!(outcome(&ent, ent.last_updated + hang - 1), Outcome::Unknown);
+        assert_eq!(outcome(&ent, ent.last_updated + hang + 1), Outcome::Hang);
+
+        ent.is_ended = true;
+        assert_eq!(outcome(&ent, ent.last_updated + hang + 1), Outcome::Unknown);
+    }
+
+    #[test]
+    fn markers_override_silence() {
+        let classifier = Classifier::new(&OutcomeRules::default()).unwrap();
+        let mut ent = entry(b"loginwindow: Login Window Started\n");
+        classifier.classify(&mut ent);
+        assert_eq!(
+            classifier.outcome(ent.outcome, false, 0, u64::MAX),
+            Outcome::Success
+        );
+    }
+}
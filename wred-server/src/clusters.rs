@@ -0,0 +1,358 @@
+//! Clusters log lines into templates across all entries.
+//!
+//! Like Drain, lines are grouped by token count and first token, and a line
+//! joins the most similar template of its group if enough tokens match,
+//! turning the tokens that differ into wildcards.
+//!
+//! The counts are saved in `log_dir` so saved entries don't have to be read
+//! again at startup, only those changed since. The file has its own
+//! [`VERSION`], apart from the entry format's, and counts in any other
+//! version are counted again instead of migrated.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use wred_server::{
+    store::Listed,
+    template::{self, TemplateStat, WILDCARD},
+};
+
+pub const FILE_NAME: &str = "templates";
+pub const MAGIC: &[u8; 4] = b"WRTC";
+/// Version of the layout of the counts written.
+pub const VERSION: u8 = 1;
+/// How often the counts are saved, besides on shutdown.
+pub const SAVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Share of tokens that must match for a line to join a template.
+const SIMILARITY: f64 = 0.5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cluster {
+    tokens: Vec<String>,
+    /// Lines of all entries.
+    total: usize,
+    entries: usize,
+}
+
+/// What was counted of an entry.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counted {
+    /// Bytes of data counted.
+    len: usize,
+    /// Lines per cluster.
+    lines: HashMap<usize, usize>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tree {
+    /// Clusters by ID, which they keep until no entry has lines in them.
+    clusters: HashMap<usize, Cluster>,
+    next: usize,
+    /// Clusters by token count and first token.
+    groups: HashMap<(usize, String), Vec<usize>>,
+    entries: HashMap<u64, Counted>,
+}
+
+fn group_key(tokens: &[String]) -> (usize, String) {
+    let first = tokens
+        .first()
+        .filter(|v| !v.contains(WILDCARD))
+        .map_or(WILDCARD, String::as_str);
+    (tokens.len(), first.to_owned())
+}
+
+/// Share of tokens equal to the template's, not counting wildcards as equal.
+#[allow(clippy::cast_precision_loss)]
+fn similarity(template: &[String], tokens: &[String]) -> f64 {
+    let same = template
+        .iter()
+        .zip(tokens)
+        .filter(|(a, b)| *a != WILDCARD && a == b)
+        .count();
+    same as f64 / tokens.len() as f64
+}
+
+impl Tree {
+    /// The most similar cluster that `tokens` would join.
+    fn find(&self, tokens: &[String]) -> Option<usize> {
+        self.groups
+            .get(&group_key(tokens))?
+            .iter()
+            .map(|&i| (i, similarity(&self.clusters[&i].tokens, tokens)))
+            .filter(|&(_, v)| v >= SIMILARITY)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+
+    /// Stops counting the lines of the entry `id`, dropping the clusters no
+    /// other entry has lines in.
+    fn remove(&mut self, id: u64) {
+        for (i, count) in self.entries.remove(&id).unwrap_or_default().lines {
+            let Some(cluster) = self.clusters.get_mut(&i) else {
+                continue;
+            };
+            cluster.total -= count;
+            cluster.entries -= 1;
+            if cluster.entries > 0 {
+                continue;
+            }
+            // The first token is never made a wildcard, so neither is the key.
+            let key = group_key(&cluster.tokens);
+            self.clusters.remove(&i);
+            if let Some(group) = self.groups.get_mut(&key) {
+                group.retain(|&v| v != i);
+                if group.is_empty() {
+                    self.groups.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, tokens: Vec<String>) -> usize {
+        if let Some(i) = self.find(&tokens) {
+            let cluster = self.clusters.get_mut(&i).unwrap();
+            for (a, b) in cluster.tokens.iter_mut().zip(&tokens) {
+                if a != b {
+                    WILDCARD.clone_into(a);
+                }
+            }
+            return i;
+        }
+        let i = self.next;
+        self.next += 1;
+        self.groups.entry(group_key(&tokens)).or_default().push(i);
+        self.clusters.insert(
+            i,
+            Cluster {
+                tokens,
+                total: 0,
+                entries: 0,
+            },
+        );
+        i
+    }
+}
+
+/// Complete lines of `data`, starting with the one `from` is in.
+fn complete_lines(data: &[u8], from: usize) -> impl Iterator<Item = (usize, &[u8])> {
+    let lines = wred_server::line::complete_lines(data, from);
+    let mut offset = lines.start;
+    data[lines].split_inclusive(|&b| b == b'\n').map(move |v| {
+        offset += v.len();
+        (offset - v.len(), v)
+    })
+}
+
+#[derive(Debug, Default)]
+pub struct Templates {
+    tree: Mutex<Tree>,
+}
+
+impl Templates {
+    /// Loads the counts saved at `path`, starting afresh if there are none.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = match std::fs::read(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        let (&version, rest) = data
+            .strip_prefix(MAGIC)
+            .and_then(<[u8]>::split_first)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not saved templates"))?;
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("saved in version {version} of the format, not {VERSION}"),
+            ));
+        }
+        let tree = postcard::from_bytes(rest).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            tree: Mutex::new(tree),
+        })
+    }
+
+    /// Saves the counts to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        let tree = postcard::to_allocvec(&*self.tree.lock().unwrap()).map_err(Error::other)?;
+        data.extend(tree);
+        crate::files::write_atomic(path, &data)
+    }
+
+    /// Drops the counts of entries that aren't `stored` or changed since,
+    /// returning the stored entries that have to be counted again.
+    pub fn retain_stored(&self, stored: &[Listed]) -> Vec<u64> {
+        let mut tree = self.tree.lock().unwrap();
+        let sizes: HashMap<_, _> = stored.iter().map(|v| (v.partial.id, v.size)).collect();
+        let changed: Vec<_> = tree
+            .entries
+            .iter()
+            .filter(|(id, v)| sizes.get(id) != Some(&(v.len as u64)))
+            .map(|(&id, _)| id)
+            .collect();
+        for &id in &changed {
+            tree.remove(id);
+        }
+        sizes
+            .into_keys()
+            .filter(|id| !tree.entries.contains_key(id))
+            .collect()
+    }
+
+    /// Counts the complete lines of `ent.data` appended at `from`.
+    pub fn apply(&self, id: u64, ent: &wred_server::LogEntry, from: usize) {
+        let mut tree = self.tree.lock().unwrap();
+        let tree = &mut *tree;
+        for (_, line) in complete_lines(&ent.data, from) {
+            let tokens = template::tokens(&String::from_utf8_lossy(line));
+            if tokens.is_empty() {
+                continue;
+            }
+            let i = tree.add(tokens);
+            let count = tree
+                .entries
+                .entry(id)
+                .or_default()
+                .lines
+                .entry(i)
+                .or_default();
+            let cluster = tree.clusters.get_mut(&i).unwrap();
+            if *count == 0 {
+                cluster.entries += 1;
+            }
+            *count += 1;
+            cluster.total += 1;
+        }
+        tree.entries.entry(id).or_default().len = ent.data.len();
+    }
+
+    /// Stops counting the lines of the entry `id`.
+    pub fn remove(&self, id: u64) {
+        self.tree.lock().unwrap().remove(id);
+    }
+
+    /// The templates of the lines of `data`, rarest first.
+    ///
+    /// Only the clusters the lines may join are copied under the lock, and
+    /// the lines are matched without it.
+    pub fn stats(&self, data: &[u8]) -> Vec<TemplateStat> {
+        let lines: Vec<_> = complete_lines(data, 0)
+            .map(|(offset, line)| (offset, template::tokens(&String::from_utf8_lossy(line))))
+            .collect();
+        // A tree of just those clusters, without the entries' counts.
+        let mut snapshot = Tree::default();
+        {
+            let tree = self.tree.lock().unwrap();
+            for (_, tokens) in &lines {
+                let key = group_key(tokens);
+                if snapshot.groups.contains_key(&key) {
+                    continue;
+                }
+                let Some(group) = tree.groups.get(&key) else {
+                    continue;
+                };
+                for &i in group {
+                    snapshot.clusters.insert(i, tree.clusters[&i].clone());
+                }
+                snapshot.groups.insert(key, group.clone());
+            }
+        }
+        let mut stats: HashMap<usize, TemplateStat> = HashMap::new();
+        for (offset, tokens) in lines {
+            let Some(i) = snapshot.find(&tokens) else {
+                continue;
+            };
+            let cluster = &snapshot.clusters[&i];
+            let stat = stats.entry(i).or_insert_with(|| TemplateStat {
+                template: cluster.tokens.join(" "),
+                count: 0,
+                total: cluster.total,
+                entries: cluster.entries,
+                offsets: Vec::new(),
+            });
+            stat.count += 1;
+            stat.offsets.push(offset);
+        }
+        let mut stats: Vec<_> = stats.into_values().collect();
+        stats.sort_by(|a, b| {
+            (a.entries, a.total, &a.template).cmp(&(b.entries, b.total, &b.template))
+        });
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{entry, TempDir};
+
+    #[test]
+    fn recounts_only_changed_entries_after_loading() {
+        let dir = TempDir::new("templates");
+        let path = dir.join(FILE_NAME);
+        assert!(Templates::load(&path).is_ok(), "nothing saved yet");
+
+        let same = entry(b"link up eth0\n");
+        let mut grown = entry(b"link up eth1\n");
+        let templates = Templates::default();
+        templates.apply(1, &same, 0);
+        templates.apply(2, &grown, 0);
+        templates.apply(3, &entry(b"link up eth2\n"), 0);
+        templates.save(&path).unwrap();
+
+        let templates = Templates::load(&path).unwrap();
+        grown.data.extend_from_slice(b"link up eth3\n");
+        // 3 was never saved, 4 was saved without being counted.
+        let stored = [
+            Listed::new(1, &same),
+            Listed::new(2, &grown),
+            Listed::new(4, &same),
+        ];
+        let mut recount = templates.retain_stored(&stored);
+        recount.sort_unstable();
+        assert_eq!(recount, [2_u64, 4]);
+        templates.apply(2, &grown, 0);
+        templates.apply(4, &same, 0);
+
+        let stats = templates.stats(b"link up eth9\n");
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].entries, stats[0].total), (3, 4));
+
+        let mut data = std::fs::read(&path).unwrap();
+        data[MAGIC.len()] = VERSION + 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(
+            Templates::load(&path).is_err(),
+            "other versions are recounted"
+        );
+        std::fs::write(&path, crate::format::header()).unwrap();
+        assert!(
+            Templates::load(&path).is_err(),
+            "entry headers aren't templates"
+        );
+    }
+
+    #[test]
+    fn removing_every_entry_empties_the_tree() {
+        let templates = Templates::default();
+        templates.apply(1, &entry(b"link up eth0\nfan speed 1200 rpm\n"), 0);
+        templates.apply(2, &entry(b"link up eth1\n"), 0);
+        templates.remove(1);
+        assert!(templates.stats(b"fan speed 1200 rpm\n").is_empty());
+        assert_eq!(templates.stats(b"link up eth2\n")[0].entries, 1);
+
+        templates.remove(2);
+        let tree = templates.tree.lock().unwrap();
+        assert!(tree.clusters.is_empty());
+        assert!(tree.groups.is_empty());
+        assert!(tree.entries.is_empty());
+    }
+}
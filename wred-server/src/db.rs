@@ -0,0 +1,385 @@
+//! Saved entries, kept in an SQLite database in the log directory.
+//!
+//! The columns hold what the list of entries shows, so listing doesn't load
+//! any log data. The entries themselves are blobs in the versioned format of
+//! `format`, kept apart from their data, which is stored in chunks so data
+//! received after saving is only appended. The schema's version is kept as
+//! the `user_version`.
+
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    sync::Mutex,
+};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use wred_server::{
+    line::Level,
+    store::{missing_data, Listed, LogStore},
+    LogEntry, LogEntryPartial, Outcome,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    id INTEGER PRIMARY KEY,
+    last_updated INTEGER NOT NULL,
+    addr TEXT NOT NULL,
+    device_id TEXT,
+    device TEXT,
+    session TEXT,
+    segments INTEGER NOT NULL,
+    has_panic INTEGER NOT NULL,
+    outcome TEXT NOT NULL,
+    is_ended INTEGER NOT NULL,
+    entry BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS metadata (
+    id INTEGER NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (id, key)
+);
+CREATE TABLE IF NOT EXISTS labels (
+    id INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    severity TEXT NOT NULL,
+    PRIMARY KEY (id, label)
+);
+CREATE TABLE IF NOT EXISTS chunks (
+    id INTEGER NOT NULL,
+    start INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (id, start)
+);
+";
+
+/// Statements bringing the schema from each version to the next, starting
+/// from an empty database at version 0.
+const MIGRATIONS: &[&str] = &[SCHEMA];
+
+fn is_stored(tx: &Transaction<'_>, id: u64) -> Result<bool> {
+    tx.query_row("SELECT 1 FROM entries WHERE id = ?1", [id], |_| Ok(()))
+        .optional()
+        .map(|v| v.is_some())
+        .map_err(Error::other)
+}
+
+/// Writes the entry `id` but its data.
+fn write_entry(tx: &Transaction<'_>, id: u64, ent: &LogEntry) -> Result<()> {
+    let blob = crate::format::encode(&ent.without_data())?;
+    tx.execute(
+        "INSERT OR REPLACE INTO entries (id, last_updated, addr, device_id, device, session,
+            segments, has_panic, outcome, is_ended, entry)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            id,
+            ent.last_updated,
+            ent.addr.to_string(),
+            ent.device_id,
+            ent.device,
+            ent.session.map(|v| v.to_string()),
+            ent.segments.len(),
+            !ent.panics.is_empty(),
+            ent.outcome.to_string(),
+            ent.is_ended,
+            blob,
+        ],
+    )
+    .map_err(Error::other)?;
+    tx.execute("DELETE FROM metadata WHERE id = ?1", [id])
+        .map_err(Error::other)?;
+    for (k, v) in &ent.metadata {
+        tx.execute(
+            "INSERT INTO metadata (id, key, value) VALUES (?1, ?2, ?3)",
+            params![id, k, v],
+        )
+        .map_err(Error::other)?;
+    }
+    tx.execute("DELETE FROM labels WHERE id = ?1", [id])
+        .map_err(Error::other)?;
+    for (label, severity) in &ent.labels {
+        tx.execute(
+            "INSERT INTO labels (id, label, severity) VALUES (?1, ?2, ?3)",
+            params![id, label, severity.to_string()],
+        )
+        .map_err(Error::other)?;
+    }
+    Ok(())
+}
+
+fn parse_outcome(v: &str) -> Outcome {
+    match v {
+        "Success" => Outcome::Success,
+        "Panic" => Outcome::Panic,
+        "Hang" => Outcome::Hang,
+        "Aborted" => Outcome::Aborted,
+        _ => Outcome::Unknown,
+    }
+}
+
+#[derive(Debug)]
+pub struct Database {
+    conn: Mutex<Connection>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut conn = Connection::open(path).map_err(Error::other)?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .map_err(Error::other)?;
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .map_err(Error::other)?;
+        if version > MIGRATIONS.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("database schema {version} is newer than this version supports"),
+            ));
+        }
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction().map_err(Error::other)?;
+            tx.execute_batch(sql).map_err(Error::other)?;
+            tx.pragma_update(None, "user_version", i + 1)
+                .map_err(Error::other)?;
+            tx.commit().map_err(Error::other)?;
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl LogStore for Database {
+    fn contains(&self, id: u64) -> Result<bool> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT 1 FROM entries WHERE id = ?1", [id], |_| Ok(()))
+            .optional()
+            .map(|v| v.is_some())
+            .map_err(Error::other)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<LogEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let blob: Option<Vec<u8>> = conn
+            .query_row("SELECT entry FROM entries WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(Error::other)?;
+        let Some(blob) = blob else {
+            return Ok(None);
+        };
+        let mut ent = crate::format::decode(&blob)?;
+        let mut stmt = conn
+            .prepare("SELECT data FROM chunks WHERE id = ?1 ORDER BY start")
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([id], |row| row.get::<_, Vec<u8>>(0))
+            .map_err(Error::other)?;
+        for row in rows {
+            ent.data.extend(row.map_err(Error::other)?);
+        }
+        Ok(Some(ent))
+    }
+
+    fn list(&self) -> Result<Vec<Listed>> {
+        let conn = self.conn.lock().unwrap();
+        let mut metadata: BTreeMap<u64, BTreeMap<String, String>> = BTreeMap::new();
+        let mut stmt = conn
+            .prepare("SELECT id, key, value FROM metadata")
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .map_err(Error::other)?;
+        for row in rows {
+            let (id, k, v) = row.map_err(Error::other)?;
+            metadata.entry(id).or_default().insert(k, v);
+        }
+        let mut labels: BTreeMap<u64, BTreeMap<String, Level>> = BTreeMap::new();
+        let mut stmt = conn
+            .prepare("SELECT id, label, severity FROM labels")
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+            })
+            .map_err(Error::other)?;
+        for row in rows {
+            let (id, label, severity) = row.map_err(Error::other)?;
+            let severity = Level::from_tag(&severity).unwrap_or(Level::Info);
+            labels.entry(id).or_default().insert(label, severity);
+        }
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, last_updated, addr, device_id, device, session, segments, has_panic,
+                    outcome, is_ended,
+                    (SELECT coalesce(sum(length(data)), 0) FROM chunks WHERE chunks.id = entries.id)
+                FROM entries",
+            )
+            .map_err(Error::other)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    row.get(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    row.get::<_, String>(8)?,
+                    row.get(9)?,
+                    row.get(10)?,
+                ))
+            })
+            .map_err(Error::other)?;
+        let mut listed = Vec::new();
+        for row in rows {
+            let (
+                id,
+                last_updated,
+                addr,
+                device_id,
+                device,
+                session,
+                segments,
+                has_panic,
+                outcome,
+                is_ended,
+                size,
+            ) = row.map_err(Error::other)?;
+            listed.push(Listed {
+                partial: LogEntryPartial {
+                    id,
+                    last_updated,
+                    addr: addr.parse().map_err(Error::other)?,
+                    device_id,
+                    device,
+                    session: session.and_then(|v| v.parse().ok()),
+                    segments,
+                    metadata: metadata.remove(&id).unwrap_or_default(),
+                    has_panic,
+                    outcome: parse_outcome(&outcome),
+                    labels: labels.remove(&id).unwrap_or_default(),
+                    is_saved: true,
+                    expires: None,
+                },
+                is_ended,
+                size,
+            });
+        }
+        Ok(listed)
+    }
+
+    fn append(&self, id: u64, from: usize, data: &[u8]) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(Error::other)?;
+        if !is_stored(&tx, id)? {
+            return Ok(false);
+        }
+        tx.execute(
+            "DELETE FROM chunks WHERE id = ?1 AND start >= ?2",
+            params![id, from],
+        )
+        .map_err(Error::other)?;
+        // Cuts a chunk running past `from` short.
+        tx.execute(
+            "UPDATE chunks SET data = substr(data, 1, ?2 - start)
+            WHERE id = ?1 AND start + length(data) > ?2",
+            params![id, from],
+        )
+        .map_err(Error::other)?;
+        let len: usize = tx
+            .query_row(
+                "SELECT coalesce(max(start + length(data)), 0) FROM chunks WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .map_err(Error::other)?;
+        if len < from {
+            return Err(missing_data(id, len, from));
+        }
+        if !data.is_empty() {
+            tx.execute(
+                "INSERT INTO chunks (id, start, data) VALUES (?1, ?2, ?3)",
+                params![id, from, data],
+            )
+            .map_err(Error::other)?;
+        }
+        tx.commit().map_err(Error::other)?;
+        Ok(true)
+    }
+
+    fn update(&self, id: u64, ent: &LogEntry) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(Error::other)?;
+        if !is_stored(&tx, id)? {
+            return Ok(false);
+        }
+        write_entry(&tx, id, ent)?;
+        tx.commit().map_err(Error::other)?;
+        Ok(true)
+    }
+
+    fn save(&self, id: u64, ent: &LogEntry) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(Error::other)?;
+        write_entry(&tx, id, ent)?;
+        tx.execute("DELETE FROM chunks WHERE id = ?1", [id])
+            .map_err(Error::other)?;
+        tx.execute(
+            "INSERT INTO chunks (id, start, data) VALUES (?1, 0, ?2)",
+            params![id, ent.data],
+        )
+        .map_err(Error::other)?;
+        tx.commit().map_err(Error::other)
+    }
+
+    fn delete(&self, id: u64) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(Error::other)?;
+        let n = tx
+            .execute("DELETE FROM entries WHERE id = ?1", [id])
+            .map_err(Error::other)?;
+        tx.execute("DELETE FROM metadata WHERE id = ?1", [id])
+            .map_err(Error::other)?;
+        tx.execute("DELETE FROM labels WHERE id = ?1", [id])
+            .map_err(Error::other)?;
+        tx.execute("DELETE FROM chunks WHERE id = ?1", [id])
+            .map_err(Error::other)?;
+        tx.commit().map_err(Error::other)?;
+        Ok(n > 0)
+    }
+
+    fn search(&self, needle: &[u8]) -> Result<Vec<u64>> {
+        // Entries with a chunk containing `needle` are candidates, as are
+        // those with more than one chunk, where it may span two. The blobs
+        // also hold the metadata, so candidates are checked after loading.
+        let candidates = {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare(
+                    "SELECT id FROM chunks GROUP BY id
+                    HAVING count(*) > 1 OR max(instr(data, ?1)) > 0",
+                )
+                .map_err(Error::other)?;
+            let rows = stmt
+                .query_map([needle], |row| row.get::<_, u64>(0))
+                .map_err(Error::other)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(Error::other)?
+        };
+        let mut ids = Vec::new();
+        for id in candidates {
+            if matches!(self.get(id), Ok(Some(v)) if v.contains(needle)) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
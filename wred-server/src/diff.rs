@@ -0,0 +1,235 @@
+//! Line diffs between entries.
+//!
+//! Lines are compared with kernel timestamps left out and addresses and
+//! pointers masked, as those differ between any two boots.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Edits after which the lines left between the common start and end are
+/// reported as a single hunk, bounding the time and memory spent.
+const MAX_EDITS: usize = 1024;
+
+/// Lines that differ, as ranges of line indices in each entry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hunk {
+    pub a: Range<usize>,
+    pub b: Range<usize>,
+}
+
+/// The lines of two entries with the hunks between them, so the ranges of the
+/// hunks always index into the lines they were found in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Diff {
+    pub a: Vec<String>,
+    pub b: Vec<String>,
+    pub hunks: Vec<Hunk>,
+}
+
+impl Diff {
+    #[must_use]
+    pub fn new(a: &[u8], b: &[u8]) -> Self {
+        let text = |v| String::from_utf8_lossy(v).into_owned();
+        Self {
+            a: lines(a).map(text).collect(),
+            b: lines(b).map(text).collect(),
+            hunks: diff(a, b),
+        }
+    }
+
+    /// Whether every hunk lies within the lines.
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.hunks
+            .iter()
+            .all(|v| v.a.end <= self.a.len() && v.b.end <= self.b.len())
+    }
+}
+
+/// The lines of `data` as diffed, each including its newline.
+pub fn lines(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split_inclusive(|&b| b == b'\n')
+}
+
+/// Whether a word is an address or a pointer, e.g. `0x1f` or `ffffff80a1b2c3d4`.
+fn is_address(word: &str) -> bool {
+    match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(v) => !v.is_empty() && v.chars().all(|c| c.is_ascii_hexdigit()),
+        None => {
+            word.len() >= 8
+                && word.contains(|c: char| c.is_ascii_digit())
+                && word.chars().all(|c| c.is_ascii_hexdigit())
+        }
+    }
+}
+
+/// A line as compared, without its kernel timestamp and with addresses and
+/// pointers masked.
+#[must_use]
+pub fn normalize(line: &[u8]) -> String {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_end_matches(['\r', '\n']);
+    let line = crate::line::strip_kernel_time(line).map_or(line, |(_, v)| v);
+    crate::template::mask_words(line, is_address)
+}
+
+/// Index pairs of equal lines in a shortest edit script, found with Myers'
+/// algorithm, or `None` if it takes more than `MAX_EDITS` edits.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn common<T: Eq>(a: &[T], b: &[T]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (a.len() + b.len()).min(MAX_EDITS) as isize;
+    // Furthest x on each diagonal k = x - y, at index k + max + 1.
+    let mut v = vec![0_isize; 2 * max as usize + 3];
+    let at = |k: isize| (k + max + 1) as usize;
+    let mut trace = Vec::new();
+    let mut found = None;
+    'outer: for d in 0..=max {
+        trace.push(v[at(-d)..=at(d)].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && v[at(k - 1)] < v[at(k + 1)]) {
+                v[at(k + 1)]
+            } else {
+                v[at(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[at(k)] = x;
+            if x >= n && y >= m {
+                found = Some(d);
+                break 'outer;
+            }
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..=found?).rev() {
+        // Diagonals -d..=d of the previous step, at index k + d.
+        let prev = &trace[d as usize];
+        let get = |k: isize| prev[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && get(k - 1) < get(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let (prev_x, prev_y) = if d == 0 {
+            (0, 0)
+        } else {
+            (get(prev_k), get(prev_k) - prev_k)
+        };
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            pairs.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    pairs.reverse();
+    Some(pairs)
+}
+
+/// The hunks of lines that differ between `a` and `b`, in order.
+#[must_use]
+pub fn diff(a: &[u8], b: &[u8]) -> Vec<Hunk> {
+    let a: Vec<_> = lines(a).map(normalize).collect();
+    let b: Vec<_> = lines(b).map(normalize).collect();
+    let prefix = a.iter().zip(&b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+
+    let mut hunks = Vec::new();
+    let (mut x, mut y) = (0, 0);
+    let pairs = common(a, b).unwrap_or_default();
+    for (i, j) in pairs.into_iter().chain(std::iter::once((a.len(), b.len()))) {
+        if i > x || j > y {
+            hunks.push(Hunk {
+                a: prefix + x..prefix + i,
+                b: prefix + y..prefix + j,
+            });
+        }
+        x = i + 1;
+        y = j + 1;
+    }
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(a: Range<usize>, b: Range<usize>) -> Hunk {
+        Hunk { a, b }
+    }
+
+    #[test]
+    fn diffs_empty_inputs() {
+        assert!(diff(b"", b"").is_empty());
+        assert_eq!(diff(b"", b"a\nb\n"), [hunk(0..0, 0..2)]);
+        assert_eq!(diff(b"a\n", b""), [hunk(0..1, 0..0)]);
+    }
+
+    #[test]
+    fn finds_no_hunks_in_identical_inputs() {
+        assert!(diff(b"a\nb\nc", b"a\nb\nc").is_empty());
+        assert!(
+            diff(
+                b"[    1.000000]: kext at 0xffffff7f80a1b000\n",
+                b"[    2.500000]: kext at 0xffffff7f80c2d000\n",
+            )
+            .is_empty(),
+            "timestamps and addresses are ignored"
+        );
+    }
+
+    #[test]
+    fn diffs_disjoint_inputs_as_one_hunk() {
+        assert_eq!(diff(b"a\nb\n", b"c\nd\ne\n"), [hunk(0..2, 0..3)]);
+    }
+
+    #[test]
+    fn finds_hunks_between_common_lines() {
+        assert_eq!(
+            diff(b"a\nb\nc\nd\ne\n", b"a\nx\nc\ne\nf\n"),
+            [hunk(1..2, 1..2), hunk(3..4, 3..3), hunk(5..5, 4..5)]
+        );
+    }
+
+    #[test]
+    fn gives_up_past_max_edits() {
+        let a: Vec<u8> = (0..MAX_EDITS)
+            .flat_map(|i| format!("a{i}\n").into_bytes())
+            .collect();
+        let b: Vec<u8> = (0..MAX_EDITS)
+            .flat_map(|i| format!("b{i}\n").into_bytes())
+            .collect();
+        let a = [b"start\n".as_slice(), &a[..], b"end\n".as_slice()].concat();
+        let b = [b"start\n".as_slice(), &b[..], b"end\n".as_slice()].concat();
+        assert_eq!(diff(&a, &b), [hunk(1..MAX_EDITS + 1, 1..MAX_EDITS + 1)]);
+    }
+
+    #[test]
+    fn keeps_hunks_within_the_lines() {
+        let diff = Diff::new(b"a\nb\nc", b"a\nc\nd\n");
+        assert_eq!(diff.a, ["a\n", "b\n", "c"]);
+        assert_eq!(diff.b, ["a\n", "c\n", "d\n"]);
+        assert!(diff.is_consistent());
+        let stale = Diff {
+            a: diff.a[..1].to_vec(),
+            ..diff
+        };
+        assert!(!stale.is_consistent());
+    }
+}
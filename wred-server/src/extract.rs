@@ -0,0 +1,187 @@
+//! Fills entry metadata from the log text as it arrives.
+
+use std::collections::BTreeMap;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataRule {
+    /// Metadata key to fill.
+    pub key: String,
+    /// Regex whose first capture group is the value.
+    pub pattern: String,
+    /// Names to store instead of matched values, looked up in lowercase.
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,
+}
+
+impl MetadataRule {
+    fn new(key: &str, pattern: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            pattern: pattern.to_owned(),
+            names: BTreeMap::new(),
+        }
+    }
+}
+
+const DEVICE_ID: &str = r"(?i)device[- _]?id[:=\s]+(?:<)?(?:0x)?([0-9a-f]{4})\b";
+
+const GPU_NAMES: [(&str, &str); 13] = [
+    ("15dd", "Raven"),
+    ("15d8", "Picasso"),
+    ("1636", "Renoir"),
+    ("164c", "Lucienne"),
+    ("1638", "Cezanne"),
+    ("15e7", "Barcelo"),
+    ("164d", "Rembrandt"),
+    ("731f", "Navi 10"),
+    ("7340", "Navi 14"),
+    ("73bf", "Navi 21"),
+    ("73df", "Navi 22"),
+    ("73ff", "Navi 23"),
+    ("743f", "Navi 24"),
+];
+
+/// Rules used unless the config replaces them. For each key the first rule
+/// that matches wins.
+pub fn default_rules() -> Vec<MetadataRule> {
+    vec![
+        MetadataRule::new("gpu.device_id", DEVICE_ID),
+        MetadataRule {
+            names: GPU_NAMES
+                .iter()
+                .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            ..MetadataRule::new("gpu", DEVICE_ID)
+        },
+        MetadataRule::new(
+            "macos.version",
+            r"(?i)\bmac ?os(?: x)?(?: version)?[:\s]+(1[0-9]\.[0-9]+(?:\.[0-9]+)?)",
+        ),
+        MetadataRule::new(
+            "macos.build",
+            r"(?i)\bmac ?os(?: x)?(?: version)?[:\s]+(?:[0-9.]+\s+)?\(?([0-9]{2}[A-Z][0-9]{1,4}[a-z]?)\b",
+        ),
+        MetadataRule::new("darwin.version", r"Darwin Kernel Version ([0-9.]+)"),
+        MetadataRule::new(
+            "wred.version",
+            r"(?i)\bwhateverred(?:\.kext)?\)?[(\s]+v?([0-9]+\.[0-9]+\.[0-9]+)",
+        ),
+        MetadataRule::new(
+            "lilu.version",
+            r"(?i)\blilu(?:\.kext)?\)?[(\s]+v?([0-9]+\.[0-9]+\.[0-9]+)",
+        ),
+        MetadataRule::new("boot_args", r#"(?i)boot[- ]args[:=]?[ \t]*"?([^"\r\n]+)"#),
+    ]
+}
+
+#[derive(Debug, Clone)]
+pub struct Extractor {
+    rules: Vec<(MetadataRule, Regex)>,
+}
+
+impl Extractor {
+    pub fn new(rules: &[MetadataRule]) -> Result<Self, regex::Error> {
+        Ok(Self {
+            rules: rules
+                .iter()
+                .map(|v| Ok((v.clone(), Regex::new(&v.pattern)?)))
+                .collect::<Result<_, regex::Error>>()?,
+        })
+    }
+
+    /// Fills keys that aren't set yet from the complete lines of `ent.data`,
+    /// starting with the line `from` is in. A line still being received is
+    /// looked at again once the rest of it arrives.
+    pub fn apply(&self, ent: &mut wred_server::LogEntry, from: usize) {
+        if self
+            .rules
+            .iter()
+            .all(|(v, _)| ent.metadata.contains_key(&v.key))
+        {
+            return;
+        }
+        let lines = wred_server::line::complete_lines(&ent.data, from);
+        if lines.is_empty() {
+            return;
+        }
+        let text = String::from_utf8_lossy(&ent.data[lines]);
+        for (rule, re) in &self.rules {
+            if ent.metadata.contains_key(&rule.key) {
+                continue;
+            }
+            let Some(value) = re.captures(&text).and_then(|v| v.get(1)) else {
+                continue;
+            };
+            let value = value.as_str().trim();
+            let value = rule
+                .names
+                .get(&value.to_ascii_lowercase())
+                .map_or(value, String::as_str);
+            ent.metadata.insert(rule.key.clone(), value.to_owned());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::entry;
+
+    fn extract(data: &[u8]) -> BTreeMap<String, String> {
+        let mut ent = entry(data);
+        Extractor::new(&default_rules()).unwrap().apply(&mut ent, 0);
+        ent.metadata
+    }
+
+    #[test]
+    fn extracts_defaults_from_sample_lines() {
+        let metadata = extract(
+            b"\
+Darwin Kernel Version 22.6.0: Wed Jul  5 22:22:05 PDT 2023; root:xnu-8796.141.3~6/RELEASE_X86_64
+macOS Version 13.5 (22G74)
+Lilu (1.6.7) starting
+WhateverRed: init: WhateverRed v1.0.2 loaded
+WhateverRed:        rad: Found GPU, device-id: 0x1636
+boot-args: \"-v keepsyms=1 -wredbeta\"
+",
+        );
+        let expected = [
+            ("boot_args", "-v keepsyms=1 -wredbeta"),
+            ("darwin.version", "22.6.0"),
+            ("gpu", "Renoir"),
+            ("gpu.device_id", "1636"),
+            ("lilu.version", "1.6.7"),
+            ("macos.build", "22G74"),
+            ("macos.version", "13.5"),
+            ("wred.version", "1.0.2"),
+        ];
+        let expected: BTreeMap<_, _> = expected
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        assert_eq!(metadata, expected);
+    }
+
+    #[test]
+    fn waits_for_complete_lines() {
+        let extractor = Extractor::new(&default_rules()).unwrap();
+        let mut ent = entry(b"Lilu (1.6");
+        extractor.apply(&mut ent, 0);
+        assert!(ent.metadata.is_empty());
+
+        let from = ent.data.len();
+        ent.data.extend_from_slice(b".7) starting\n");
+        extractor.apply(&mut ent, from);
+        assert_eq!(ent.metadata["lilu.version"], "1.6.7");
+    }
+
+    #[test]
+    fn keeps_unknown_device_ids() {
+        let metadata = extract(b"device-id: <0x9999>\n");
+        assert_eq!(metadata["gpu.device_id"], "9999");
+        assert_eq!(metadata["gpu"], "9999");
+    }
+}
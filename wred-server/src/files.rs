@@ -0,0 +1,204 @@
+//! Saved entries, kept as files in the log directory.
+//!
+//! Each entry is stored as `{id}.data`, its data exactly as received, and
+//! `{id}.entry`, the entry without the data in the versioned format of
+//! `format`. Data received after saving is appended to the data file, while
+//! the entry file is rewritten when the rest of the entry changes.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use wred_server::{
+    store::{missing_data, Listed, LogStore},
+    LogEntry,
+};
+
+/// Writes `data` to `path` through a temporary file, so a crash never leaves
+/// it half written.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(tmp, path)
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn entry_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id}.entry"))
+    }
+
+    fn data_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id}.data"))
+    }
+
+    /// IDs of the stored entries, from the names of the entry files.
+    fn ids(&self) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for ent in std::fs::read_dir(&self.dir)? {
+            let path = ent?.path();
+            if path.extension().is_some_and(|v| v == "entry") {
+                if let Some(id) = path.file_stem().and_then(|v| v.to_str()?.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// The entry without its data.
+    fn read_entry(&self, id: u64) -> Result<Option<LogEntry>> {
+        match std::fs::read(self.entry_path(id)) {
+            Ok(v) => crate::format::decode(&v).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl LogStore for FileStore {
+    fn list(&self) -> Result<Vec<Listed>> {
+        let mut listed = Vec::new();
+        for id in self.ids()? {
+            // One unreadable entry shouldn't hide all the others.
+            let ent = match self.read_entry(id) {
+                Ok(Some(v)) => v,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!("Skipping unreadable saved log {id}: {e}");
+                    continue;
+                }
+            };
+            listed.push(Listed {
+                size: std::fs::metadata(self.data_path(id)).map_or(0, |v| v.len()),
+                ..Listed::new(id, &ent)
+            });
+        }
+        Ok(listed)
+    }
+
+    fn get(&self, id: u64) -> Result<Option<LogEntry>> {
+        let Some(mut ent) = self.read_entry(id)? else {
+            return Ok(None);
+        };
+        ent.data = std::fs::read(self.data_path(id))?;
+        Ok(Some(ent))
+    }
+
+    fn contains(&self, id: u64) -> Result<bool> {
+        self.entry_path(id).try_exists()
+    }
+
+    fn append(&self, id: u64, from: usize, data: &[u8]) -> Result<bool> {
+        if !self.contains(id)? {
+            return Ok(false);
+        }
+        let mut file = OpenOptions::new().append(true).open(self.data_path(id))?;
+        let len = usize::try_from(file.metadata()?.len()).unwrap_or(usize::MAX);
+        if len < from {
+            return Err(missing_data(id, len, from));
+        }
+        file.set_len(from as u64)?;
+        file.write_all(data)?;
+        Ok(true)
+    }
+
+    fn update(&self, id: u64, ent: &LogEntry) -> Result<bool> {
+        if !self.contains(id)? {
+            return Ok(false);
+        }
+        let blob = crate::format::encode(&ent.without_data())?;
+        write_atomic(&self.entry_path(id), &blob)?;
+        Ok(true)
+    }
+
+    fn save(&self, id: u64, ent: &LogEntry) -> Result<()> {
+        // The entry file is written last, as it marks the entry stored.
+        write_atomic(&self.data_path(id), &ent.data)?;
+        let blob = crate::format::encode(&ent.without_data())?;
+        write_atomic(&self.entry_path(id), &blob)
+    }
+
+    fn delete(&self, id: u64) -> Result<bool> {
+        let existed = match std::fs::remove_file(self.entry_path(id)) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        match std::fs::remove_file(self.data_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(existed),
+        }
+    }
+
+    fn search(&self, needle: &[u8]) -> Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for id in self.ids()? {
+            // Unreadable entries are logged when listed.
+            if matches!(self.get(id), Ok(Some(v)) if v.contains(needle)) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+/// Moves entries saved as `{id}.log` files by older versions into `store`,
+/// renaming the files to `{id}.log.imported`.
+///
+/// Files that can't be imported are logged and left in place, so they can be
+/// looked at and imported on a later start.
+pub fn import_legacy(store: &dyn LogStore, dir: &Path) -> Result<usize> {
+    let import = |path: &Path| -> Result<()> {
+        let (id, ent) = crate::format::decode_legacy(&std::fs::read(path)?)?;
+        store.save(id, &ent)?;
+        std::fs::rename(path, path.with_extension("log.imported"))
+    };
+    let mut n = 0;
+    for ent in std::fs::read_dir(dir)? {
+        let path = ent?.path();
+        if !path.is_file() || path.extension().is_none_or(|v| v != "log") {
+            continue;
+        }
+        match import(&path) {
+            Ok(()) => n += 1,
+            Err(e) => println!("Failed importing {}: {e}", path.display()),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use wred_server::store::MemoryStore;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn imports_legacy_files_and_leaves_bad_ones() {
+        let dir = TempDir::new("files-legacy");
+        // `(id, entry)` in the layout of the first release.
+        let addr: std::net::SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let data = postcard::to_allocvec(&(7_u64, 1_u64, addr, "boot\n")).unwrap();
+        std::fs::write(dir.join("7.log"), data).unwrap();
+        std::fs::write(dir.join("8.log"), b"not a log").unwrap();
+
+        let store = MemoryStore::default();
+        assert_eq!(import_legacy(&store, dir.path()).unwrap(), 1);
+        assert_eq!(store.get(7).unwrap().unwrap().data, b"boot\n");
+        assert!(dir.join("7.log.imported").exists());
+        assert!(dir.join("8.log").exists());
+    }
+}
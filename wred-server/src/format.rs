@@ -0,0 +1,200 @@
+//! Versioned encoding of entries on disk.
+//!
+//! Encoded entries start with [`MAGIC`] and a version byte, followed by the
+//! postcard-encoded entry in that version's layout. Older layouts are decoded
+//! into their own structs and migrated to the current one. The first release
+//! saved entries without a header, as `(id, entry)` in `{id}.log` files, which
+//! are read by [`decode_legacy`] only.
+//!
+//! Changing `LogEntry` or what it contains therefore means bumping
+//! [`VERSION`], and moving a copy of the old layout into a module here with a
+//! migration from it.
+
+use std::io::{Error, ErrorKind, Result};
+
+use serde::de::DeserializeOwned;
+use wred_server::LogEntry;
+
+pub const MAGIC: &[u8; 4] = b"WRED";
+/// Version of the layout written.
+pub const VERSION: u8 = 1;
+
+/// The layout of the first release, saved without a header.
+mod v0 {
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize)]
+    pub struct LogEntry {
+        pub last_updated: u64,
+        pub addr: std::net::SocketAddr,
+        pub data: String,
+    }
+
+    impl From<LogEntry> for wred_server::LogEntry {
+        fn from(v: LogEntry) -> Self {
+            Self {
+                last_updated: v.last_updated,
+                addr: v.addr,
+                device_id: None,
+                device: None,
+                session: None,
+                is_ended: true,
+                is_closed: true,
+                metadata: std::collections::BTreeMap::new(),
+                data: v.data.into_bytes(),
+                segments: Vec::new(),
+                panics: Vec::new(),
+                outcome: wred_server::Outcome::Unknown,
+                labels: std::collections::BTreeMap::new(),
+            }
+        }
+    }
+}
+
+/// Decodes `data` as `T`, only if nothing is left over.
+fn decode_exact<T: DeserializeOwned>(data: &[u8]) -> Option<T> {
+    match postcard::take_from_bytes(data) {
+        Ok((v, [])) => Some(v),
+        _ => None,
+    }
+}
+
+/// The header written before data in the current layout.
+pub fn header() -> [u8; 5] {
+    let mut v = [VERSION; 5];
+    v[..4].copy_from_slice(MAGIC);
+    v
+}
+
+/// Splits the header off `data`, returning the version with the rest, or
+/// `None` if there is no header.
+pub fn split_header(data: &[u8]) -> Option<(u8, &[u8])> {
+    let (&version, rest) = data.strip_prefix(MAGIC)?.split_first()?;
+    Some((version, rest))
+}
+
+/// Checks that data of `version` can be read.
+pub fn check_version(version: u8) -> Result<()> {
+    if version > VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("written by a newer version of the format ({version} > {VERSION})"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn encode(ent: &LogEntry) -> Result<Vec<u8>> {
+    let mut v = header().to_vec();
+    v.extend(postcard::to_allocvec(ent).map_err(Error::other)?);
+    Ok(v)
+}
+
+/// Decodes an entry of any version, migrating it to the current layout.
+pub fn decode(data: &[u8]) -> Result<LogEntry> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "not an encoded entry");
+    let (version, rest) = split_header(data).ok_or_else(invalid)?;
+    check_version(version)?;
+    decode_exact(rest).ok_or_else(invalid)
+}
+
+/// Decodes an `(id, entry)` pair from a `{id}.log` file of the first release.
+pub fn decode_legacy(data: &[u8]) -> Result<(u64, LogEntry)> {
+    decode_exact::<(u64, v0::LogEntry)>(data)
+        .map(|(id, v)| (id, v.into()))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a saved log"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+
+    /// The layout of `v0::LogEntry`, which is only ever decoded.
+    #[derive(Serialize)]
+    struct V0 {
+        last_updated: u64,
+        addr: std::net::SocketAddr,
+        data: String,
+    }
+
+    fn v0() -> V0 {
+        V0 {
+            last_updated: 1234,
+            addr: "10.0.0.2:5000".parse().unwrap(),
+            data: "boot\n".to_owned(),
+        }
+    }
+
+    fn current() -> LogEntry {
+        let (_, mut ent) = decode_legacy(V0_FILE).unwrap();
+        ent.device_id = Some("hackintosh".to_owned());
+        ent.is_ended = false;
+        ent.metadata.insert("board".to_owned(), "Mac-1".to_owned());
+        ent.data = vec![0xFF, b'\n'];
+        ent
+    }
+
+    /// A `{id}.log` file exactly as the first release wrote it, with
+    /// `postcard::to_allocvec(&(id, entry))`.
+    const V0_FILE: &[u8] = &[
+        42, // id
+        0xD2, 0x09, // last_updated: 1234
+        0, 10, 0, 0, 2, 0x88, 0x27, // addr: V4, 10.0.0.2, port 5000
+        5, b'b', b'o', b'o', b't', b'\n', // data
+    ];
+
+    #[test]
+    fn decodes_files_of_the_first_release() {
+        let (id, ent) = decode_legacy(V0_FILE).unwrap();
+        assert_eq!(id, 42);
+        assert_eq!(ent.last_updated, 1234);
+        assert_eq!(ent.addr, v0().addr);
+        assert_eq!(ent.data, b"boot\n");
+        assert!(ent.is_ended);
+        assert!(ent.segments.is_empty());
+    }
+
+    #[test]
+    fn round_trips_current() {
+        let data = encode(&current()).unwrap();
+        assert_eq!(split_header(&data).map(|v| v.0), Some(VERSION));
+        let ent = decode(&data).unwrap();
+        assert_eq!(ent.device_id.as_deref(), Some("hackintosh"));
+        assert!(!ent.is_ended);
+        assert_eq!(ent.metadata, current().metadata);
+        assert_eq!(ent.data, current().data);
+    }
+
+    #[test]
+    fn rejects_entries_without_header() {
+        assert!(decode(&postcard::to_allocvec(&current()).unwrap()).is_err());
+        assert!(decode(&postcard::to_allocvec(&v0()).unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_newer_version() {
+        let mut data = encode(&current()).unwrap();
+        data[4] = VERSION + 1;
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut data = encode(&current()).unwrap();
+        data.push(0);
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn decodes_legacy_files() {
+        let data = postcard::to_allocvec(&(42_u64, v0())).unwrap();
+        let (id, ent) = decode_legacy(&data).unwrap();
+        assert_eq!(id, 42);
+        assert_eq!(ent.data, b"boot\n");
+
+        assert!(decode_legacy(&postcard::to_allocvec(&(43_u64, current())).unwrap()).is_err());
+        assert!(decode_legacy(b"not a log").is_err());
+    }
+}
@@ -0,0 +1,1216 @@
+//! Applies received log data to the entries.
+//!
+//! Listeners send commands to a single ingest task instead of locking the
+//! entries themselves. Slow senders therefore never hold the lock, and API
+//! readers only wait for short, batched writes. Each change is recorded in
+//! the journal, which is synced after every batch once the lock is released,
+//! and only then are commands replied to.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    sync::Arc,
+};
+
+use sequence_generator::sequence_generator;
+use tokio::sync::{mpsc, oneshot, RwLock};
+use wred_server::{protocol::Receipt, store::LogStore};
+
+use crate::{
+    classify::Classifier,
+    clusters::Templates,
+    extract::Extractor,
+    journal::{Journal, Record},
+    retention::{Eviction, Evictions, Reason},
+    rules::Labeler,
+    symbols::Symbols,
+};
+
+/// Entries held in memory, which are those still being received and, until
+/// the store takes them over, saved ones.
+pub type Live = Arc<RwLock<HashMap<u64, wred_server::LogEntry>>>;
+
+/// Connections from the same IP within this many microseconds of the last
+/// update are merged into the same entry.
+pub const MERGE_WINDOW: u64 = 60_000_000;
+const QUEUE_LEN: usize = 1024;
+/// Commands applied per write lock, so readers get a turn under load.
+const BATCH_LEN: usize = 64;
+
+/// Line appended to an entry in place of data dropped because of `reason`.
+pub fn truncation_marker(reason: &str) -> String {
+    format!("\n[NETDBG: {reason}, further data was dropped]\n")
+}
+
+fn generate_id() -> (sequence_generator::SequenceProperties, u64) {
+    let properties = wred_server::get_id_props();
+    let id = sequence_generator::generate_id(&properties).unwrap();
+    (properties, id)
+}
+
+pub fn now_micros() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |v| u64::try_from(v.as_micros()).unwrap_or(u64::MAX))
+}
+
+/// Where incoming data comes from, used to decide which entry it belongs to.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub addr: std::net::SocketAddr,
+    pub device_id: Option<String>,
+    /// Device name from the sender's ingest token.
+    pub device: Option<String>,
+    pub session: Option<uuid::Uuid>,
+    /// When the sender connected.
+    pub connected: u64,
+    /// Whether the data may be merged into an existing entry.
+    pub merge: bool,
+    /// Metadata for entries opened for this source.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Source {
+    pub fn new(addr: std::net::SocketAddr) -> Self {
+        Self {
+            addr,
+            device_id: None,
+            device: None,
+            session: None,
+            connected: now_micros(),
+            merge: true,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    /// Whether data from this source belongs in `ent`.
+    ///
+    /// Senders identifying their session are grouped by it, legacy senders by
+    /// IP address and time since the last update. Data is never merged across
+    /// devices.
+    fn matches(&self, ent: &wred_server::LogEntry, now: u64) -> bool {
+        self.merge
+            && ent.device == self.device
+            && self.session.map_or_else(
+                || {
+                    ent.session.is_none()
+                        && !ent.is_closed
+                        && ent.addr.ip() == self.addr.ip()
+                        && now.saturating_sub(ent.last_updated) < MERGE_WINDOW
+                },
+                |session| ent.session == Some(session) && !ent.is_ended,
+            )
+    }
+}
+
+/// Works out what the data says as it arrives.
+#[derive(Debug)]
+pub struct Analysis {
+    pub extractor: Extractor,
+    pub symbols: Arc<Symbols>,
+    pub classifier: Classifier,
+    pub labeler: Arc<Labeler>,
+    pub templates: Arc<Templates>,
+}
+
+impl Analysis {
+    /// Updates the entry `id` for the data appended at `from`.
+    fn apply(&self, id: u64, ent: &mut wred_server::LogEntry, from: usize) {
+        self.extractor.apply(ent, from);
+        wred_server::panic::scan(&mut ent.panics, &ent.data, from);
+        self.symbols.symbolicate(&mut ent.panics);
+        self.classifier.apply(ent, from);
+        self.labeler.apply(ent, from);
+        self.templates.apply(id, ent, from);
+    }
+
+    /// Works out everything again for the entry `id`, e.g. after replaying
+    /// it from the journal.
+    pub fn reapply(&self, id: u64, ent: &mut wred_server::LogEntry) {
+        self.extractor.apply(ent, 0);
+        ent.panics.clear();
+        wred_server::panic::scan(&mut ent.panics, &ent.data, 0);
+        self.symbols.symbolicate(&mut ent.panics);
+        self.classifier.classify(ent);
+        self.labeler.relabel(ent);
+        self.templates.apply(id, ent, 0);
+    }
+}
+
+/// Segment of an entry that a sender appends to.
+#[derive(Debug, Clone, Copy)]
+pub struct Cursor {
+    pub id: u64,
+    segment: usize,
+}
+
+type Update = Box<dyn FnOnce(&mut wred_server::LogEntry) + Send>;
+type UpdateAll = Box<dyn FnMut(&mut wred_server::LogEntry) + Send>;
+/// Reply to a command, sent once the journal has been synced.
+type Reply = Box<dyn FnOnce() + Send>;
+
+fn reply<T: Send + 'static>(replies: &mut Vec<Reply>, tx: oneshot::Sender<T>, v: T) {
+    replies.push(Box::new(move || {
+        let _e = tx.send(v);
+    }));
+}
+
+enum Command {
+    Append {
+        cursor: Option<Cursor>,
+        source: Source,
+        data: Vec<u8>,
+        reply: oneshot::Sender<(Cursor, Option<Receipt>)>,
+    },
+    Update {
+        id: u64,
+        update: Update,
+    },
+    /// Runs the update on every entry, replying with their IDs. Changes
+    /// aren't journaled, as they are worked out again on replay.
+    UpdateAll {
+        update: UpdateAll,
+        reply: oneshot::Sender<HashSet<u64>>,
+    },
+    Delete {
+        id: u64,
+        reply: oneshot::Sender<Option<wred_server::LogEntry>>,
+    },
+    /// Saves the entry to the store, after which its data no longer counts
+    /// towards the unsaved budget. Replies with whether it's stored.
+    Save {
+        id: u64,
+        reply: oneshot::Sender<io::Result<bool>>,
+    },
+    /// Evicts unsaved entries not updated for `max_age` microseconds, and
+    /// drops saved ones no longer receiving data from memory, keeping
+    /// sessions that weren't ended for as long. Entries of legacy senders
+    /// that nothing can be merged into anymore are closed first.
+    Evict {
+        max_age: Option<u64>,
+        now: u64,
+    },
+}
+
+/// Tracks bytes held in unsaved entries.
+#[derive(Debug, Default)]
+struct Budget {
+    max: usize,
+    used: usize,
+    /// Unsaved bytes per entry, and whether data was already dropped from it.
+    entries: HashMap<u64, (usize, bool)>,
+    /// Saved entries, which are exempt.
+    saved: HashSet<u64>,
+    evictions: Arc<Evictions>,
+}
+
+impl Budget {
+    /// Accounts for `data` being appended to `id`, returning what should be
+    /// appended instead if the budget is exhausted even after evicting other
+    /// entries.
+    fn charge(&mut self, id: u64, data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.saved.contains(&id) {
+            return Some(data);
+        }
+        let fits = self.fits(data.len());
+        let ent = self.entries.entry(id).or_default();
+        if fits {
+            self.used += data.len();
+            ent.0 += data.len();
+            Some(data)
+        } else if ent.1 {
+            None
+        } else {
+            ent.1 = true;
+            println!("Unsaved log budget exhausted, dropping data for {id}");
+            Some(truncation_marker("unsaved log budget exhausted").into_bytes())
+        }
+    }
+
+    /// Whether `len` more unsaved bytes fit.
+    const fn fits(&self, len: usize) -> bool {
+        self.used + len <= self.max
+    }
+
+    fn release(&mut self, id: u64) {
+        if let Some((n, _)) = self.entries.remove(&id) {
+            self.used -= n;
+        }
+    }
+
+    /// Stops accounting for `id`, which was deleted.
+    fn forget(&mut self, id: u64) {
+        self.release(id);
+        self.saved.remove(&id);
+    }
+}
+
+fn open_entry(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    source: &Source,
+    now: u64,
+) -> Receipt {
+    if let Some((&id, _)) = logs.iter().find(|(_, e)| source.matches(e, now)) {
+        return Receipt { id, merged: true };
+    }
+    let (properties, id) = generate_id();
+
+    logs.insert(
+        id,
+        wred_server::LogEntry {
+            last_updated: sequence_generator::decode_id_unix_epoch_micros(id, &properties),
+            addr: source.addr,
+            device_id: source.device_id.clone(),
+            device: source.device.clone(),
+            session: source.session,
+            is_ended: false,
+            is_closed: false,
+            metadata: source.metadata.clone(),
+            data: Vec::new(),
+            segments: Vec::new(),
+            panics: Vec::new(),
+            outcome: wred_server::Outcome::Unknown,
+            labels: BTreeMap::new(),
+        },
+    );
+    Receipt { id, merged: false }
+}
+
+/// Returns the segment for `source` in `ent`, continuing its open one if any,
+/// so datagrams from the same address don't each get their own.
+fn open_segment(ent: &mut wred_server::LogEntry, source: &Source) -> usize {
+    if let Some(i) = ent
+        .segments
+        .iter()
+        .rposition(|v| v.addr == source.addr && v.disconnected.is_none())
+    {
+        return i;
+    }
+    ent.segments.push(wred_server::Segment {
+        addr: source.addr,
+        connected: source.connected,
+        disconnected: None,
+        arrivals: Vec::new(),
+    });
+    ent.segments.len() - 1
+}
+
+/// Appends `data` to a segment of `ent`, returning where it starts.
+fn push_data(ent: &mut wred_server::LogEntry, segment: usize, time: u64, data: &[u8]) -> usize {
+    let from = ent.data.len();
+    ent.segments[segment].arrivals.push(wred_server::Arrival {
+        time,
+        offset: from,
+        len: data.len(),
+    });
+    ent.data.extend_from_slice(data);
+    from
+}
+
+fn journal_write(journal: &mut Journal, record: &Record<'_>) {
+    if let Err(e) = journal.write(record) {
+        println!("Failed to write to the journal: {e}");
+    }
+}
+
+/// Records the fields of `ent` that updates change.
+fn journal_update(journal: &mut Journal, id: u64, ent: &wred_server::LogEntry) {
+    journal_write(
+        journal,
+        &Record::Update {
+            id,
+            is_ended: ent.is_ended,
+            is_closed: ent.is_closed,
+            metadata: Cow::Borrowed(&ent.metadata),
+            disconnected: ent.segments.iter().map(|v| v.disconnected).collect(),
+        },
+    );
+}
+
+/// Changes to saved entries not yet written to the store, which is done
+/// after each batch, once the lock is released.
+#[derive(Debug, Default)]
+struct Pending {
+    /// Where the data not yet stored starts, per entry.
+    appends: HashMap<u64, usize>,
+    /// Entries whose fields besides the data changed.
+    updates: HashSet<u64>,
+    /// Entries to save, with whom to tell.
+    saves: Vec<(u64, oneshot::Sender<io::Result<bool>>)>,
+    /// Saved entries to drop from memory once stored.
+    forget: Vec<u64>,
+}
+
+impl Pending {
+    /// Copies what has to be written of the entries in `logs` that are still
+    /// there, so the store can be written to without holding the lock.
+    fn take(self, logs: &HashMap<u64, wred_server::LogEntry>) -> Writes {
+        Writes {
+            appends: self
+                .appends
+                .into_iter()
+                .filter_map(|(id, from)| Some((id, from, logs.get(&id)?.data[from..].to_vec())))
+                .collect(),
+            updates: self
+                .updates
+                .into_iter()
+                .filter_map(|id| Some((id, logs.get(&id)?.without_data())))
+                .collect(),
+            saves: self
+                .saves
+                .into_iter()
+                .map(|(id, tx)| (id, logs.get(&id).cloned(), tx))
+                .collect(),
+            forget: self.forget,
+        }
+    }
+}
+
+/// Changes taken from [`Pending`], to be written to the store.
+#[derive(Debug, Default)]
+struct Writes {
+    appends: Vec<(u64, usize, Vec<u8>)>,
+    updates: Vec<(u64, wred_server::LogEntry)>,
+    /// Entries to save as they were held, if they still were.
+    saves: Vec<(
+        u64,
+        Option<wred_server::LogEntry>,
+        oneshot::Sender<io::Result<bool>>,
+    )>,
+    forget: Vec<u64>,
+}
+
+impl Writes {
+    /// Writes the changes to `store`, returning the entries it took over and
+    /// those that can be dropped from memory.
+    fn write(self, store: &dyn LogStore, replies: &mut Vec<Reply>) -> (Vec<u64>, Vec<u64>) {
+        let mut failed = HashSet::new();
+        for (id, from, data) in self.appends {
+            if let Err(e) = store.append(id, from, &data) {
+                println!("Failed to store data for {id}: {e}");
+                failed.insert(id);
+            }
+        }
+        for (id, ent) in self.updates {
+            if let Err(e) = store.update(id, &ent) {
+                println!("Failed to store {id}: {e}");
+                failed.insert(id);
+            }
+        }
+        let mut saved = Vec::new();
+        for (id, ent, tx) in self.saves {
+            let res = match ent {
+                Some(ent) => store.save(id, &ent).map(|()| {
+                    saved.push(id);
+                    true
+                }),
+                // Already saved, and no longer held.
+                None => store.contains(id),
+            };
+            reply(replies, tx, res);
+        }
+        let mut forget = self.forget;
+        forget.retain(|id| !failed.contains(id));
+        (saved, forget)
+    }
+}
+
+fn apply(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    pending: &mut Pending,
+    journal: &mut Journal,
+    budget: &mut Budget,
+    analysis: &Analysis,
+    replies: &mut Vec<Reply>,
+    cmd: Command,
+) {
+    match cmd {
+        Command::Append {
+            cursor,
+            source,
+            data,
+            reply,
+        } => {
+            let now = now_micros();
+            let (cursor, receipt) = cursor.filter(|v| logs.contains_key(&v.id)).map_or_else(
+                || {
+                    let receipt = open_entry(logs, &source, now);
+                    let id = receipt.id;
+                    let ent = logs.get_mut(&id).unwrap();
+                    if !receipt.merged {
+                        journal_write(
+                            journal,
+                            &Record::Entry {
+                                id,
+                                ent: Cow::Borrowed(ent),
+                            },
+                        );
+                    }
+                    let n = ent.segments.len();
+                    let segment = open_segment(ent, &source);
+                    if segment == n {
+                        journal_write(
+                            journal,
+                            &Record::Segment {
+                                id,
+                                segment: Cow::Borrowed(&ent.segments[n]),
+                            },
+                        );
+                    }
+                    (Cursor { id, segment }, Some(receipt))
+                },
+                |v| (v, None),
+            );
+            make_room(logs, journal, budget, analysis, cursor.id, data.len(), now);
+            let ent = logs.get_mut(&cursor.id).unwrap();
+            ent.last_updated = now;
+            if let Some(data) = budget.charge(cursor.id, data) {
+                journal_write(
+                    journal,
+                    &Record::Data {
+                        id: cursor.id,
+                        segment: cursor.segment,
+                        time: now,
+                        data: Cow::Borrowed(&data[..]),
+                    },
+                );
+                let from = push_data(ent, cursor.segment, now, &data);
+                analysis.apply(cursor.id, ent, from);
+                if budget.saved.contains(&cursor.id) {
+                    pending.appends.entry(cursor.id).or_insert(from);
+                }
+            }
+            self::reply(replies, reply, (cursor, receipt));
+        }
+        Command::Update { id, update } => {
+            if let Some(ent) = logs.get_mut(&id) {
+                update(ent);
+                journal_update(journal, id, ent);
+                if budget.saved.contains(&id) {
+                    pending.updates.insert(id);
+                }
+            }
+        }
+        Command::UpdateAll { mut update, reply } => {
+            for (&id, ent) in logs.iter_mut() {
+                update(ent);
+                if budget.saved.contains(&id) {
+                    pending.updates.insert(id);
+                }
+            }
+            self::reply(replies, reply, logs.keys().copied().collect());
+        }
+        Command::Delete { id, reply } => {
+            let ent = logs.remove(&id);
+            if ent.is_some() {
+                journal_write(journal, &Record::Delete { id });
+            }
+            budget.forget(id);
+            analysis.templates.remove(id);
+            self::reply(replies, reply, ent);
+        }
+        Command::Save { id, reply } => pending.saves.push((id, reply)),
+        Command::Evict { max_age, now } => {
+            close_legacy(logs, pending, journal, budget, max_age, now);
+            if let Some(max_age) = max_age {
+                let expired: Vec<_> = logs
+                    .iter()
+                    .filter(|(id, v)| {
+                        !budget.saved.contains(id) && now.saturating_sub(v.last_updated) > max_age
+                    })
+                    .map(|(&id, _)| id)
+                    .collect();
+                for id in expired {
+                    evict(logs, journal, budget, analysis, id, Reason::UnsavedAge, now);
+                }
+            }
+            // Their stored copy is brought up to date before they are dropped.
+            pending.forget.extend(
+                logs.iter()
+                    .filter(|(id, v)| budget.saved.contains(id) && is_done(v, max_age, now))
+                    .map(|(&id, _)| id),
+            );
+        }
+    }
+}
+
+/// Closes the entries of legacy senders that nothing more can be merged
+/// into, as the senders can't tell when they are done. They aren't ended, as
+/// a sender going quiet may mean the boot hung.
+fn close_legacy(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    pending: &mut Pending,
+    journal: &mut Journal,
+    budget: &Budget,
+    max_age: Option<u64>,
+    now: u64,
+) {
+    for (&id, ent) in logs.iter_mut() {
+        if !ent.is_ended && !ent.is_closed && ent.session.is_none() && is_done(ent, max_age, now) {
+            ent.is_closed = true;
+            journal_update(journal, id, ent);
+            if budget.saved.contains(&id) {
+                pending.updates.insert(id);
+            }
+        }
+    }
+}
+
+/// Whether no more data can be appended to `ent`.
+///
+/// Only entries in memory are found for new data, so sessions that weren't
+/// ended are kept until they reach the unsaved age limit, as their sender may
+/// reconnect after a reboot.
+fn is_done(ent: &wred_server::LogEntry, max_age: Option<u64>, now: u64) -> bool {
+    let disconnected = ent.segments.iter().all(|v| v.disconnected.is_some());
+    let idle = now.saturating_sub(ent.last_updated);
+    // Legacy senders reconnecting soon after are merged into the entry.
+    ent.is_ended
+        || ent.is_closed
+        || (disconnected
+            && if ent.session.is_some() {
+                max_age.is_some_and(|v| idle > v)
+            } else {
+                idle >= MERGE_WINDOW
+            })
+}
+
+/// Removes the unsaved entry `id` because of `reason`.
+fn evict(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    journal: &mut Journal,
+    budget: &mut Budget,
+    analysis: &Analysis,
+    id: u64,
+    reason: Reason,
+    now: u64,
+) {
+    let Some(ent) = logs.remove(&id) else {
+        return;
+    };
+    journal_write(journal, &Record::Delete { id });
+    budget.forget(id);
+    analysis.templates.remove(id);
+    budget.evictions.record(Eviction {
+        id,
+        time: now,
+        reason,
+        size: ent.data.len() as u64,
+    });
+}
+
+/// Evicts the oldest unsaved entries other than `id` until `len` more bytes
+/// of it fit in the budget.
+fn make_room(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    journal: &mut Journal,
+    budget: &mut Budget,
+    analysis: &Analysis,
+    id: u64,
+    len: usize,
+    now: u64,
+) {
+    if budget.saved.contains(&id) || budget.fits(len) {
+        return;
+    }
+    let mut unsaved: Vec<_> = logs
+        .iter()
+        .filter(|(&k, _)| k != id && !budget.saved.contains(&k))
+        .map(|(&k, v)| (v.last_updated, k))
+        .collect();
+    unsaved.sort_unstable();
+    for (_, k) in unsaved {
+        if budget.fits(len) {
+            break;
+        }
+        evict(
+            logs,
+            journal,
+            budget,
+            analysis,
+            k,
+            Reason::UnsavedBytes,
+            now,
+        );
+    }
+}
+
+/// Rebuilds the entries from the journal's records.
+///
+/// Entries saved in `store` are left to it from then on, once the data and
+/// changes journaled after it last got them are written to it. Those that
+/// can't be are kept in memory as unsaved.
+pub fn replay(
+    logs: &mut HashMap<u64, wred_server::LogEntry>,
+    records: Vec<Record<'static>>,
+    store: &dyn LogStore,
+    analysis: &Analysis,
+) {
+    for record in records {
+        match record {
+            Record::Entry { id, ent } => {
+                logs.insert(id, ent.into_owned());
+            }
+            Record::Segment { id, segment } => {
+                if let Some(ent) = logs.get_mut(&id) {
+                    ent.segments.push(segment.into_owned());
+                }
+            }
+            Record::Data {
+                id,
+                segment,
+                time,
+                data,
+            } => {
+                if let Some(ent) = logs.get_mut(&id).filter(|v| segment < v.segments.len()) {
+                    ent.last_updated = time;
+                    push_data(ent, segment, time, &data);
+                }
+            }
+            Record::Update {
+                id,
+                is_ended,
+                is_closed,
+                metadata,
+                disconnected,
+            } => {
+                if let Some(ent) = logs.get_mut(&id) {
+                    ent.is_ended = is_ended;
+                    ent.is_closed = is_closed;
+                    ent.metadata = metadata.into_owned();
+                    for (seg, v) in ent.segments.iter_mut().zip(disconnected) {
+                        seg.disconnected = v;
+                    }
+                }
+            }
+            Record::Delete { id } => {
+                logs.remove(&id);
+            }
+        }
+    }
+    let stored: HashMap<_, _> = match store.list() {
+        Ok(v) => v.into_iter().map(|v| (v.partial.id, v.size)).collect(),
+        Err(e) => {
+            println!("Failed listing saved logs: {e}");
+            HashMap::new()
+        }
+    };
+    logs.retain(|&id, ent| {
+        let Some(&size) = stored.get(&id) else {
+            return true;
+        };
+        let from = usize::try_from(size).map_or(ent.data.len(), |v| v.min(ent.data.len()));
+        let res = store
+            .append(id, from, &ent.data[from..])
+            .and_then(|_| store.update(id, ent));
+        if let Err(e) = &res {
+            println!("Failed to store journaled data for {id}: {e}");
+        }
+        res.is_err()
+    });
+    for (&id, ent) in logs.iter_mut() {
+        analysis.reapply(id, ent);
+    }
+}
+
+/// Writes `writes` to `store` on a blocking thread, as [`Writes::write`].
+async fn store_writes(
+    store: &Arc<dyn LogStore>,
+    writes: Writes,
+    replies: &mut Vec<Reply>,
+) -> (Vec<u64>, Vec<u64>) {
+    if writes.appends.is_empty() && writes.updates.is_empty() && writes.saves.is_empty() {
+        return (Vec::new(), writes.forget);
+    }
+    let store = Arc::clone(store);
+    let written = tokio::task::spawn_blocking(move || {
+        let mut replies = Vec::new();
+        let written = writes.write(store.as_ref(), &mut replies);
+        (written, replies)
+    })
+    .await;
+    match written {
+        Ok((written, v)) => {
+            replies.extend(v);
+            written
+        }
+        Err(e) => {
+            println!("Failed to write to the store: {e}");
+            (Vec::new(), Vec::new())
+        }
+    }
+}
+
+async fn run(
+    logs: Live,
+    store: Arc<dyn LogStore>,
+    mut journal: Journal,
+    mut budget: Budget,
+    analysis: Analysis,
+    mut rx: mpsc::Receiver<Command>,
+) {
+    // Entries replayed from the journal are unsaved.
+    for (&id, ent) in logs.read().await.iter() {
+        budget.used += ent.data.len();
+        budget.entries.insert(id, (ent.data.len(), false));
+    }
+    while let Some(cmd) = rx.recv().await {
+        let mut replies = Vec::new();
+        let mut pending = Pending::default();
+        let mut guard = logs.write().await;
+        let mut apply_cmd = |cmd| {
+            apply(
+                &mut guard,
+                &mut pending,
+                &mut journal,
+                &mut budget,
+                &analysis,
+                &mut replies,
+                cmd,
+            );
+        };
+        apply_cmd(cmd);
+        for _ in 1..BATCH_LEN {
+            match rx.try_recv() {
+                Ok(cmd) => apply_cmd(cmd),
+                Err(_) => break,
+            }
+        }
+        drop(guard);
+
+        // Only this task writes to the entries, so they are compacted from
+        // under a read lock, which doesn't hold up readers, rather than from
+        // a copy of them. The journal is written on a blocking thread.
+        let entries = Arc::clone(&logs).read_owned().await;
+        let (v, res) = tokio::task::spawn_blocking(move || {
+            let res = journal.commit(entries.iter());
+            (journal, res)
+        })
+        .await
+        .expect("Journal writer panicked");
+        journal = v;
+        // Changes that may not be on disk aren't confirmed.
+        if let Err(e) = res {
+            println!("Failed to write to the journal: {e}");
+            replies.clear();
+        }
+        let writes = pending.take(&*logs.read().await);
+        let (saved, forget) = store_writes(&store, writes, &mut replies).await;
+        for id in saved {
+            budget.release(id);
+            budget.saved.insert(id);
+        }
+        if !forget.is_empty() {
+            let mut logs = logs.write().await;
+            for id in forget {
+                logs.remove(&id);
+                budget.forget(id);
+            }
+        }
+        // Senders are only told their data was received once it's on disk.
+        for reply in replies {
+            reply();
+        }
+    }
+}
+
+/// Handle to the ingest task.
+#[derive(Debug, Clone)]
+pub struct Ingest {
+    tx: mpsc::Sender<Command>,
+}
+
+impl Ingest {
+    pub fn spawn(
+        logs: Live,
+        store: Arc<dyn LogStore>,
+        journal: Journal,
+        max_unsaved_bytes: usize,
+        evictions: Arc<Evictions>,
+        analysis: Analysis,
+    ) -> Self {
+        let budget = Budget {
+            max: max_unsaved_bytes,
+            evictions,
+            ..Budget::default()
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(run(logs, store, journal, budget, analysis, rx));
+        Self { tx }
+    }
+
+    /// Appends data at `cursor`, opening an entry first if there is none yet.
+    ///
+    /// Returns the receipt for the sender if an entry was opened.
+    pub async fn append(
+        &self,
+        cursor: &mut Option<Cursor>,
+        source: &Source,
+        data: Vec<u8>,
+    ) -> Option<Receipt> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(Command::Append {
+                cursor: *cursor,
+                source: source.clone(),
+                data,
+                reply,
+            })
+            .await
+            .ok()?;
+        let (new_cursor, receipt) = rx.await.ok()?;
+        *cursor = Some(new_cursor);
+        receipt
+    }
+
+    /// Records that the sender appending at `cursor` disconnected.
+    pub async fn disconnect(&self, cursor: Cursor) {
+        let now = now_micros();
+        self.update(cursor.id, move |ent| {
+            if let Some(v) = ent.segments.get_mut(cursor.segment) {
+                v.disconnected = Some(now);
+            }
+        })
+        .await;
+    }
+
+    /// Runs `update` on the entry `id` if it still exists.
+    ///
+    /// Only changes to `is_ended`, `is_closed`, the metadata and when
+    /// segments were disconnected are journaled.
+    pub async fn update(
+        &self,
+        id: u64,
+        update: impl FnOnce(&mut wred_server::LogEntry) + Send + 'static,
+    ) {
+        let _e = self
+            .tx
+            .send(Command::Update {
+                id,
+                update: Box::new(update),
+            })
+            .await;
+    }
+
+    /// Runs `update` on every entry in memory, returning their IDs.
+    pub async fn update_all(
+        &self,
+        update: impl FnMut(&mut wred_server::LogEntry) + Send + 'static,
+    ) -> HashSet<u64> {
+        let (reply, rx) = oneshot::channel();
+        let cmd = Command::UpdateAll {
+            update: Box::new(update),
+            reply,
+        };
+        if self.tx.send(cmd).await.is_err() {
+            return HashSet::new();
+        }
+        rx.await.unwrap_or_default()
+    }
+
+    /// Removes the entry `id` from memory, returning it if it was there.
+    pub async fn delete(&self, id: u64) -> Option<wred_server::LogEntry> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Command::Delete { id, reply }).await.ok()?;
+        rx.await.ok()?
+    }
+
+    /// Evicts unsaved entries not updated for `max_age` microseconds, and
+    /// drops saved ones no longer receiving data from memory.
+    pub async fn evict(&self, max_age: Option<u64>, now: u64) {
+        let _e = self.tx.send(Command::Evict { max_age, now }).await;
+    }
+
+    /// Saves the entry `id` in memory to the store, returning whether it's
+    /// stored, which it may already be.
+    pub async fn save(&self, id: u64) -> io::Result<bool> {
+        let (reply, rx) = oneshot::channel();
+        let closed = || io::Error::other("ingest task stopped");
+        self.tx
+            .send(Command::Save { id, reply })
+            .await
+            .map_err(|_| closed())?;
+        rx.await.map_err(|_| closed())?
+    }
+
+    /// Stores a complete upload as a new entry.
+    pub async fn upload(
+        &self,
+        addr: std::net::SocketAddr,
+        device_id: Option<String>,
+        device: Option<String>,
+        metadata: BTreeMap<String, String>,
+        data: &[u8],
+    ) -> Option<Receipt> {
+        let source = Source {
+            device_id,
+            device,
+            merge: false,
+            metadata,
+            ..Source::new(addr)
+        };
+        let mut cursor = None;
+        let receipt = self.append(&mut cursor, &source, data.to_vec()).await;
+        if let Some(cursor) = cursor {
+            // Uploads are complete, so they never count as hung.
+            self.update(cursor.id, |ent| ent.is_ended = true).await;
+            self.disconnect(cursor).await;
+        }
+        receipt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wred_server::store::MemoryStore;
+
+    use super::*;
+    use crate::{
+        classify::OutcomeRules,
+        journal::FILE_NAME,
+        testing::{entry, TempDir},
+    };
+
+    /// State of the ingest task, driven without the task.
+    struct Harness {
+        logs: HashMap<u64, wred_server::LogEntry>,
+        pending: Pending,
+        journal: Journal,
+        budget: Budget,
+        analysis: Analysis,
+        dir: TempDir,
+    }
+
+    impl Harness {
+        fn new(name: &str, max_unsaved_bytes: usize) -> Self {
+            let dir = TempDir::new(name);
+            let (journal, _) = Journal::open(&dir.join(FILE_NAME)).unwrap();
+            Self {
+                logs: HashMap::new(),
+                pending: Pending::default(),
+                journal,
+                budget: Budget {
+                    max: max_unsaved_bytes,
+                    ..Budget::default()
+                },
+                analysis: analysis(),
+                dir,
+            }
+        }
+
+        fn apply(&mut self, cmd: Command) {
+            let mut replies = Vec::new();
+            apply(
+                &mut self.logs,
+                &mut self.pending,
+                &mut self.journal,
+                &mut self.budget,
+                &self.analysis,
+                &mut replies,
+                cmd,
+            );
+            for reply in replies {
+                reply();
+            }
+        }
+
+        /// Appends `data` from a new connection of `source`.
+        fn append(&mut self, source: &Source, data: &[u8]) -> Receipt {
+            let (reply, mut rx) = oneshot::channel();
+            self.apply(Command::Append {
+                cursor: None,
+                source: source.clone(),
+                data: data.to_vec(),
+                reply,
+            });
+            rx.try_recv().unwrap().1.unwrap()
+        }
+
+        fn disconnect(&mut self, id: u64) {
+            for segment in &mut self.logs.get_mut(&id).unwrap().segments {
+                segment.disconnected.get_or_insert(1);
+            }
+        }
+    }
+
+    fn analysis() -> Analysis {
+        Analysis {
+            extractor: Extractor::new(&crate::extract::default_rules()).unwrap(),
+            symbols: Arc::new(Symbols::load()),
+            classifier: Classifier::new(&OutcomeRules::default()).unwrap(),
+            labeler: Arc::new(Labeler::load()),
+            templates: Arc::default(),
+        }
+    }
+
+    fn source(addr: &str) -> Source {
+        Source::new(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn merges_reconnections_within_the_window() {
+        let mut h = Harness::new("ingest-merge", usize::MAX);
+        let first = h.append(&source("10.0.0.2:5000"), b"one\n");
+        assert!(!first.merged);
+        h.disconnect(first.id);
+        let again = h.append(&source("10.0.0.2:5001"), b"two\n");
+        assert_eq!(
+            again,
+            Receipt {
+                id: first.id,
+                merged: true
+            }
+        );
+        let ent = &h.logs[&first.id];
+        assert_eq!(ent.data, b"one\ntwo\n");
+        assert_eq!(ent.segments.len(), 2);
+
+        let other = h.append(&source("10.0.0.3:5000"), b"other\n");
+        assert!(!other.merged);
+        assert_ne!(other.id, first.id);
+
+        h.disconnect(first.id);
+        h.logs.get_mut(&first.id).unwrap().last_updated -= MERGE_WINDOW;
+        let later = h.append(&source("10.0.0.2:5002"), b"three\n");
+        assert!(!later.merged);
+        assert_ne!(later.id, first.id);
+    }
+
+    #[test]
+    fn keeps_sessions_apart() {
+        let mut h = Harness::new("ingest-sessions", usize::MAX);
+        let session = |v| Source {
+            session: Some(uuid::Uuid::from_u128(v)),
+            ..source("10.0.0.2:5000")
+        };
+        let a = h.append(&session(1), b"a\n");
+        let b = h.append(&session(2), b"b\n");
+        assert!(!b.merged);
+        assert_ne!(b.id, a.id);
+        assert!(!h.append(&source("10.0.0.2:5001"), b"legacy\n").merged);
+        assert_eq!(
+            h.append(&session(1), b"a\n"),
+            Receipt {
+                id: a.id,
+                merged: true
+            }
+        );
+
+        h.logs.get_mut(&a.id).unwrap().is_ended = true;
+        assert!(!h.append(&session(1), b"a\n").merged);
+    }
+
+    #[test]
+    fn tells_when_entries_are_done() {
+        let mut ent = entry(b"boot\n");
+        ent.segments.push(wred_server::Segment {
+            addr: ent.addr,
+            connected: 1,
+            disconnected: None,
+            arrivals: Vec::new(),
+        });
+        let now = ent.last_updated + MERGE_WINDOW;
+        assert!(!is_done(&ent, None, now), "still connected");
+        ent.segments[0].disconnected = Some(1);
+        assert!(!is_done(&ent, None, now - 1), "may still be merged into");
+        assert!(is_done(&ent, None, now));
+
+        ent.session = Some(uuid::Uuid::nil());
+        assert!(
+            !is_done(&ent, None, u64::MAX),
+            "may reconnect after a reboot"
+        );
+        assert!(!is_done(&ent, Some(MERGE_WINDOW), now));
+        assert!(is_done(&ent, Some(MERGE_WINDOW), now + 1));
+
+        ent.segments[0].disconnected = None;
+        ent.is_ended = true;
+        assert!(is_done(&ent, None, 0));
+        ent.is_ended = false;
+        ent.is_closed = true;
+        assert!(is_done(&ent, None, 0));
+    }
+
+    #[test]
+    fn charges_unsaved_data_until_the_budget_is_spent() {
+        let mut budget = Budget {
+            max: 10,
+            ..Budget::default()
+        };
+        assert_eq!(budget.charge(1, vec![0; 6]), Some(vec![0; 6]));
+        let marker = truncation_marker("unsaved log budget exhausted").into_bytes();
+        assert_eq!(budget.charge(1, vec![0; 6]), Some(marker));
+        assert_eq!(budget.charge(1, vec![0; 6]), None);
+        assert_eq!(budget.charge(2, vec![0; 4]), Some(vec![0; 4]));
+        budget.saved.insert(3);
+        assert_eq!(budget.charge(3, vec![0; 100]), Some(vec![0; 100]));
+        assert_eq!(budget.used, 10);
+
+        budget.release(1);
+        assert_eq!(budget.used, 4);
+    }
+
+    #[test]
+    fn evicts_the_oldest_unsaved_entries_to_make_room() {
+        let mut h = Harness::new("ingest-make-room", 8);
+        let a = h.append(&source("10.0.0.2:5000"), b"aaa\n");
+        let b = h.append(&source("10.0.0.3:5000"), b"bbb\n");
+        h.logs.get_mut(&a.id).unwrap().last_updated = 2;
+        h.logs.get_mut(&b.id).unwrap().last_updated = 1;
+        let c = h.append(&source("10.0.0.4:5000"), b"ccc\n");
+        assert!(h.logs.contains_key(&a.id));
+        assert!(!h.logs.contains_key(&b.id));
+        assert_eq!(h.budget.used, 8);
+
+        // Saved entries are exempt, however old.
+        h.budget.release(a.id);
+        h.budget.saved.insert(a.id);
+        h.logs.get_mut(&a.id).unwrap().last_updated = 0;
+        let d = h.append(&source("10.0.0.5:5000"), b"ddddddd\n");
+        assert!(h.logs.contains_key(&a.id));
+        assert!(!h.logs.contains_key(&c.id));
+        assert!(h.logs.contains_key(&d.id));
+
+        let evicted: Vec<_> = h
+            .budget
+            .evictions
+            .recent()
+            .iter()
+            .map(|v| (v.id, v.reason))
+            .collect();
+        assert_eq!(
+            evicted,
+            [(b.id, Reason::UnsavedBytes), (c.id, Reason::UnsavedBytes)]
+        );
+    }
+
+    #[test]
+    fn replay_restores_entries_and_stores_journaled_data() {
+        let mut h = Harness::new("ingest-replay", usize::MAX);
+        let store = MemoryStore::default();
+        let unsaved = h.append(&source("10.0.0.2:5000"), b"unsaved\n");
+        let saved = h.append(&source("10.0.0.3:5000"), b"saved\n");
+        let deleted = h.append(&source("10.0.0.4:5000"), b"deleted\n");
+        store.save(saved.id, &h.logs[&saved.id]).unwrap();
+        // Journaled, but lost before the store got it.
+        h.append(&source("10.0.0.3:5001"), b"more\n");
+        let (reply, _rx) = oneshot::channel();
+        h.apply(Command::Delete {
+            id: deleted.id,
+            reply,
+        });
+        h.journal.sync().unwrap();
+
+        let (_, records) = Journal::open(&h.dir.join(FILE_NAME)).unwrap();
+        let mut logs = HashMap::new();
+        replay(&mut logs, records, &store, &analysis());
+        assert_eq!(logs.keys().collect::<Vec<_>>(), [&unsaved.id]);
+        let ent = &logs[&unsaved.id];
+        assert_eq!(ent.data, b"unsaved\n");
+        assert_eq!(ent.segments.len(), 1);
+        let ent = store.get(saved.id).unwrap().unwrap();
+        assert_eq!(ent.data, b"saved\nmore\n");
+        assert_eq!(ent.segments.len(), 2);
+    }
+}
@@ -0,0 +1,422 @@
+//! Write-ahead journal of ingested data, so entries survive restarts and
+//! crashes whether saved or not.
+//!
+//! The ingest task records each change to the entries before applying it,
+//! and the journal is replayed on startup. Each record is framed by its
+//! length and an FNV-1a checksum, so a record torn by a crash is detected and
+//! dropped along with anything after it.
+//!
+//! The journal is compacted into one record per entry in memory on startup
+//! and whenever it has grown well past that. Saved entries are kept while in
+//! memory, so data appended to them survives until the store has it, and
+//! entries only in the store are left to it.
+//! It's also rewritten that way after failing to write to it, as a record
+//! left torn in the middle would hide the ones after it on replay.
+//!
+//! It starts with the header of `format`, as its records hold entries in the
+//! layout of that version.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use wred_server::{LogEntry, Segment};
+
+/// Name of the journal in the log directory.
+pub const FILE_NAME: &str = "journal";
+
+/// Bytes the journal may grow past twice its compacted size before it's
+/// compacted again.
+const COMPACT_SLACK: u64 = 64 * 1024 * 1024;
+
+/// A change to the entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Record<'a> {
+    /// An entry as a whole, as opened or when the journal was compacted.
+    Entry {
+        id: u64,
+        ent: Cow<'a, LogEntry>,
+    },
+    /// A connection started appending to an entry.
+    Segment {
+        id: u64,
+        segment: Cow<'a, Segment>,
+    },
+    /// Data appended to a segment of an entry at `time`.
+    Data {
+        id: u64,
+        segment: usize,
+        time: u64,
+        data: Cow<'a, [u8]>,
+    },
+    /// The fields an update may have changed.
+    Update {
+        id: u64,
+        is_ended: bool,
+        is_closed: bool,
+        metadata: Cow<'a, BTreeMap<String, String>>,
+        disconnected: Vec<Option<u64>>,
+    },
+    Delete {
+        id: u64,
+    },
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |h, &b| {
+        (h ^ u32::from(b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Decodes a record written in the layout of `version`.
+///
+/// Like entries in `format`, records of older versions are to be decoded into
+/// copies of their old layout and migrated.
+fn decode_record(version: u8, payload: &[u8]) -> Result<Record<'static>> {
+    match version {
+        crate::format::VERSION => {
+            postcard::from_bytes(payload).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        }
+        // The journal was added in version 1, so nothing older has records.
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no journal records in format version {version}"),
+        )),
+    }
+}
+
+/// Reads the records in `data` written in the layout of `version`, returning
+/// them with the length of the intact part.
+///
+/// Reading stops at a record that is cut short or fails its checksum, as a
+/// crash leaves them. A record that is intact but can't be decoded is an
+/// error instead, so it isn't mistaken for a torn tail and dropped.
+fn read_records(version: u8, data: &[u8]) -> Result<(Vec<Record<'static>>, usize)> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let Some(payload) = data.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        if checksum(payload) != sum {
+            break;
+        }
+        let record = decode_record(version, payload)
+            .map_err(|e| Error::new(e.kind(), format!("journal record at byte {pos}: {e}")))?;
+        records.push(record);
+        pos += 8 + len;
+    }
+    Ok((records, pos))
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    /// Records not yet written to the file.
+    buf: Vec<u8>,
+    /// Bytes written, including those still buffered.
+    len: u64,
+    /// Length after the last compaction.
+    compacted_len: u64,
+    /// Whether writing failed since the last compaction, so the file may
+    /// lack records or hold a torn one.
+    broken: bool,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, returning it with the records to replay.
+    ///
+    /// A torn tail is cut off, while a record that can't be decoded fails
+    /// opening and leaves the journal as it is. A journal of an older version
+    /// has to be compacted before anything is written to it, which rewrites it
+    /// in the current one.
+    pub fn open(path: &Path) -> Result<(Self, Vec<Record<'static>>)> {
+        let data = match std::fs::read(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // A journal cut short while its header was written has no records.
+        let (version, start) = if crate::format::header().starts_with(&data) {
+            (crate::format::VERSION, 0)
+        } else {
+            let (version, _) = crate::format::split_header(&data)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "not a journal"))?;
+            crate::format::check_version(version)?;
+            (version, crate::format::header().len())
+        };
+        let (records, valid) = read_records(version, &data[start..])?;
+        let valid = start + valid;
+        if valid < data.len() {
+            println!(
+                "Dropping {} bytes of torn journal records",
+                data.len() - valid
+            );
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.set_len(valid as u64)?;
+        let mut len = valid as u64;
+        if len == 0 {
+            file.write_all(&crate::format::header())?;
+            len = crate::format::header().len() as u64;
+        }
+        Ok((
+            Self {
+                path: path.to_owned(),
+                file,
+                buf: Vec::new(),
+                len,
+                compacted_len: len,
+                broken: false,
+            },
+            records,
+        ))
+    }
+
+    fn write_to(w: &mut impl Write, record: &Record<'_>) -> Result<u64> {
+        let payload = postcard::to_allocvec(record).map_err(Error::other)?;
+        let len = u32::try_from(payload.len()).map_err(Error::other)?;
+        w.write_all(&len.to_le_bytes())?;
+        w.write_all(&checksum(&payload).to_le_bytes())?;
+        w.write_all(&payload)?;
+        Ok(8 + payload.len() as u64)
+    }
+
+    /// Buffers `record` in memory, to be written by the next [`Self::sync`].
+    pub fn write(&mut self, record: &Record<'_>) -> Result<()> {
+        match Self::write_to(&mut self.buf, record) {
+            Ok(n) => {
+                self.len += n;
+                Ok(())
+            }
+            Err(e) => {
+                self.broken = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes the buffered records through to the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        let res = self
+            .file
+            .write_all(&self.buf)
+            .and_then(|()| self.file.sync_data());
+        self.buf.clear();
+        if res.is_err() {
+            self.broken = true;
+        }
+        res
+    }
+
+    /// Whether the journal has grown enough to be worth compacting, or has to
+    /// be rewritten.
+    pub const fn needs_compaction(&self) -> bool {
+        self.broken || self.len > 2 * self.compacted_len + COMPACT_SLACK
+    }
+
+    /// Makes the records written so far durable, compacting the journal into
+    /// `entries` instead when it's due or writing fails.
+    ///
+    /// On error, the records may not have reached the disk, and the journal is
+    /// rewritten on the next call.
+    pub fn commit<'a>(
+        &mut self,
+        entries: impl Iterator<Item = (&'a u64, &'a LogEntry)> + Clone,
+    ) -> Result<()> {
+        if !self.needs_compaction() {
+            match self.sync() {
+                Ok(()) => return Ok(()),
+                Err(e) => println!("Failed to write to the journal, rewriting it: {e}"),
+            }
+        }
+        self.compact(entries)
+    }
+
+    /// Replaces the journal with one record per entry of `entries`, dropping
+    /// the buffered records.
+    pub fn compact<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a u64, &'a LogEntry)>,
+    ) -> Result<()> {
+        self.buf.clear();
+        self.broken = true;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        w.write_all(&crate::format::header())?;
+        let mut len = crate::format::header().len() as u64;
+        for (&id, ent) in entries {
+            let record = Record::Entry {
+                id,
+                ent: Cow::Borrowed(ent),
+            };
+            len += Self::write_to(&mut w, &record)?;
+        }
+        w.into_inner()
+            .map_err(std::io::IntoInnerError::into_error)?
+            .sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.len = len;
+        self.compacted_len = len;
+        self.broken = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::testing::{entry, TempDir};
+
+    fn framed(record: &Record<'_>) -> Vec<u8> {
+        let mut v = Vec::new();
+        Journal::write_to(&mut v, record).unwrap();
+        v
+    }
+
+    fn data(id: u64, data: &[u8]) -> Record<'_> {
+        Record::Data {
+            id,
+            segment: 0,
+            time: 2,
+            data: Cow::Borrowed(data),
+        }
+    }
+
+    #[test]
+    fn replays_written_records() {
+        let dir = TempDir::new("journal-replay");
+        let path = dir.join(FILE_NAME);
+        let (mut journal, records) = Journal::open(&path).unwrap();
+        assert!(records.is_empty());
+        let ent = entry(b"boot\n");
+        journal
+            .write(&Record::Entry {
+                id: 1,
+                ent: Cow::Borrowed(&ent),
+            })
+            .unwrap();
+        journal.write(&data(1, b"more\n")).unwrap();
+        journal.write(&Record::Delete { id: 2 }).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert!(matches!(
+            records.as_slice(),
+            [
+                Record::Entry { id: 1, ent },
+                Record::Data { id: 1, data, .. },
+                Record::Delete { id: 2 },
+            ] if ent.data == b"boot\n" && data.as_ref() == b"more\n"
+        ));
+    }
+
+    #[test]
+    fn truncates_torn_tail() {
+        let dir = TempDir::new("journal-torn");
+        let path = dir.join(FILE_NAME);
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        journal.write(&data(1, b"kept\n")).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let torn = framed(&data(1, b"torn\n"));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..torn.len() - 2]).unwrap();
+        drop(file);
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        let mut corrupt = framed(&data(1, b"flip\n"));
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&corrupt).unwrap();
+        drop(file);
+        let (_, records) = Journal::open(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn rewrites_after_failed_write() {
+        let dir = TempDir::new("journal-rewrite");
+        let path = dir.join(FILE_NAME);
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        let mut ent = entry(b"boot\n");
+        journal
+            .write(&Record::Entry {
+                id: 1,
+                ent: Cow::Borrowed(&ent),
+            })
+            .unwrap();
+        journal.sync().unwrap();
+
+        // A write that failed halfway, leaving a torn record mid-file.
+        let torn = framed(&data(1, b"torn\n"));
+        journal.file.write_all(&torn[..torn.len() - 2]).unwrap();
+        journal.broken = true;
+        journal.write(&data(1, b"more\n")).unwrap();
+        ent.data.extend_from_slice(b"more\n");
+        let entries = HashMap::from([(1, ent)]);
+        journal.commit(entries.iter()).unwrap();
+        assert!(!journal.needs_compaction());
+        drop(journal);
+
+        let (_, records) = Journal::open(&path).unwrap();
+        assert!(matches!(
+            records.as_slice(),
+            [Record::Entry { id: 1, ent }] if ent.data == b"boot\nmore\n"
+        ));
+    }
+
+    #[test]
+    fn keeps_intact_records_that_fail_to_decode() {
+        let dir = TempDir::new("journal-undecodable");
+        let path = dir.join(FILE_NAME);
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        journal.write(&data(1, b"kept\n")).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+
+        // Intact framing around a payload no record decodes from, as records
+        // of another layout would be.
+        let payload = [0xFF; 4];
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&4_u32.to_le_bytes()).unwrap();
+        file.write_all(&checksum(&payload).to_le_bytes()).unwrap();
+        file.write_all(&payload).unwrap();
+        drop(file);
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        assert!(Journal::open(&path).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let dir = TempDir::new("journal-newer");
+        let path = dir.join(FILE_NAME);
+        let mut v = crate::format::header().to_vec();
+        v[4] = crate::format::VERSION + 1;
+        v.extend(framed(&Record::Delete { id: 1 }));
+        std::fs::write(&path, &v).unwrap();
+
+        assert!(Journal::open(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), v);
+    }
+}
@@ -8,22 +8,194 @@
 )]
 #![allow(clippy::module_name_repetitions)]
 
+use std::{borrow::Cow, collections::BTreeMap};
+
 use sequence_generator::sequence_generator;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub mod diff;
+pub mod line;
+pub mod panic;
+pub mod protocol;
+pub mod store;
+pub mod template;
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntryPartial {
     pub id: u64,
     pub last_updated: u64,
     pub addr: std::net::SocketAddr,
+    pub device_id: Option<String>,
+    pub device: Option<String>,
+    pub session: Option<Uuid>,
+    /// Number of connections that contributed to the entry.
+    pub segments: usize,
+    pub metadata: BTreeMap<String, String>,
+    /// Whether a kernel panic was found in the entry.
+    pub has_panic: bool,
+    pub outcome: Outcome,
+    pub labels: BTreeMap<String, line::Level>,
     pub is_saved: bool,
+    /// When the entry will be evicted for its age, if unsaved and an age
+    /// limit is set.
+    pub expires: Option<u64>,
+}
+
+/// How a boot ended, as far as its log tells.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Outcome {
+    /// Still booting, or ended without a sign of how.
+    #[default]
+    Unknown,
+    /// Reached the desktop.
+    Success,
+    Panic,
+    /// Stopped sending without finishing.
+    Hang,
+    /// A kext bailed out early.
+    Aborted,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "Unknown",
+            Self::Success => "Success",
+            Self::Panic => "Panic",
+            Self::Hang => "Hang",
+            Self::Aborted => "Aborted",
+        })
+    }
+}
+
+/// Data received at one time, as a range of the entry's data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Arrival {
+    pub time: u64,
+    pub offset: usize,
+    pub len: usize,
+}
+
+/// Data an entry received over one connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub addr: std::net::SocketAddr,
+    pub connected: u64,
+    pub disconnected: Option<u64>,
+    pub arrivals: Vec<Arrival>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogEntry {
     pub last_updated: u64,
     pub addr: std::net::SocketAddr,
-    pub data: String,
+    pub device_id: Option<String>,
+    /// Device name from the ingest token the sender presented.
+    pub device: Option<String>,
+    pub session: Option<Uuid>,
+    /// Whether the sender said the boot is over, or uploaded the whole log.
+    pub is_ended: bool,
+    /// Whether the sender went away without ending the entry, so nothing
+    /// more is merged into it. It may still have hung.
+    pub is_closed: bool,
+    pub metadata: BTreeMap<String, String>,
+    /// Bytes exactly as received.
+    pub data: Vec<u8>,
+    /// Connections the data came from. Segments of connections merged into
+    /// the same entry may interleave.
+    pub segments: Vec<Segment>,
+    /// Kernel panics found in the data.
+    pub panics: Vec<panic::PanicReport>,
+    /// Outcome from the marker lines seen so far. Hangs are only told apart
+    /// when the entry is listed, as they depend on the time since the last
+    /// update.
+    pub outcome: Outcome,
+    /// Labels of the rules matching the data, with their severity.
+    pub labels: BTreeMap<String, line::Level>,
+}
+
+impl LogEntry {
+    /// The entry as listed, with its marker outcome.
+    #[must_use]
+    pub fn partial(&self, id: u64) -> LogEntryPartial {
+        LogEntryPartial {
+            id,
+            last_updated: self.last_updated,
+            addr: self.addr,
+            device_id: self.device_id.clone(),
+            device: self.device.clone(),
+            session: self.session,
+            segments: self.segments.len(),
+            metadata: self.metadata.clone(),
+            has_panic: !self.panics.is_empty(),
+            outcome: self.outcome,
+            labels: self.labels.clone(),
+            is_saved: false,
+            expires: None,
+        }
+    }
+
+    /// A copy of the entry without its data, e.g. to store apart from it.
+    #[must_use]
+    pub fn without_data(&self) -> Self {
+        Self {
+            last_updated: self.last_updated,
+            addr: self.addr,
+            device_id: self.device_id.clone(),
+            device: self.device.clone(),
+            session: self.session,
+            is_ended: self.is_ended,
+            is_closed: self.is_closed,
+            metadata: self.metadata.clone(),
+            data: Vec::new(),
+            segments: self.segments.clone(),
+            panics: self.panics.clone(),
+            outcome: self.outcome,
+            labels: self.labels.clone(),
+        }
+    }
+
+    /// Whether the data contains `needle`.
+    #[must_use]
+    pub fn contains(&self, needle: &[u8]) -> bool {
+        needle.is_empty() || self.data.windows(needle.len()).any(|v| v == needle)
+    }
+
+    /// The data as text, with invalid UTF-8 replaced by U+FFFD.
+    #[must_use]
+    pub fn text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.data)
+    }
+
+    /// All arrivals with the index of their segment, in the order of the data.
+    #[must_use]
+    pub fn arrivals(&self) -> Vec<(usize, Arrival)> {
+        let mut v: Vec<_> = self
+            .segments
+            .iter()
+            .enumerate()
+            .flat_map(|(i, seg)| seg.arrivals.iter().map(move |&v| (i, v)))
+            .collect();
+        v.sort_unstable_by_key(|(_, v)| v.offset);
+        v
+    }
+}
+
+/// Run of log data that is either valid UTF-8 or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chunk<'a> {
+    Text(&'a str),
+    Binary(&'a [u8]),
+}
+
+/// Splits `data` into text and binary runs, e.g. to show the latter as hex.
+pub fn chunks(data: &[u8]) -> impl Iterator<Item = Chunk<'_>> {
+    data.utf8_chunks().flat_map(|v| {
+        let text = (!v.valid().is_empty()).then(|| Chunk::Text(v.valid()));
+        let binary = (!v.invalid().is_empty()).then(|| Chunk::Binary(v.invalid()));
+        text.into_iter().chain(binary)
+    })
 }
 
 #[must_use]
@@ -0,0 +1,232 @@
+//! Parsing of log lines printed by Lilu plugins such as `WhateverRed`.
+//!
+//! Lilu's logging macros print `PRODUCT: %10s: ` followed by the message, with
+//! the module name right-aligned, and prefix debug messages with `@ `:
+//!
+//! ```text
+//! [   12.345678] WhateverRed:        rad: @ wrapInitializeAsic: (DBG) powering up
+//! ```
+//!
+//! The kernel timestamp, level tag and function name are all optional, and
+//! lines in any other format are kept with only the level recognised.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Bytes looked back on before appended data for the start of its line, so
+/// scanning each append doesn't cost more the longer a line gets.
+pub const LOOKBACK: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Level {
+    Debug,
+    Info,
+    Warning,
+    Error,
+    Panic,
+}
+
+impl Level {
+    pub const ALL: [Self; 5] = [
+        Self::Debug,
+        Self::Info,
+        Self::Warning,
+        Self::Error,
+        Self::Panic,
+    ];
+
+    /// Parses a level tag such as `DBG` or `WARNING`.
+    #[must_use]
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag.to_ascii_uppercase().as_str() {
+            "DBG" | "DEBUG" => Some(Self::Debug),
+            "INF" | "INFO" => Some(Self::Info),
+            "WARN" | "WARNING" => Some(Self::Warning),
+            "ERR" | "ERROR" => Some(Self::Error),
+            "PANIC" => Some(Self::Panic),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Debug => "DBG",
+            Self::Info => "INFO",
+            Self::Warning => "WARN",
+            Self::Error => "ERR",
+            Self::Panic => "PANIC",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Offset of the line in the entry's data.
+    pub offset: usize,
+    /// Length of the line, including its newline.
+    pub len: usize,
+    /// Kernel timestamp in microseconds since boot.
+    pub kernel_time: Option<u64>,
+    /// Kext that printed the line, e.g. `WhateverRed`.
+    pub product: Option<String>,
+    pub module: Option<String>,
+    pub function: Option<String>,
+    pub level: Level,
+    /// The line without any of the recognised prefixes.
+    pub message: String,
+}
+
+fn is_ident(v: &str) -> bool {
+    !v.is_empty()
+        && v.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':' || c == '-')
+        && !v.starts_with(':')
+        && !v.ends_with(':')
+}
+
+/// The complete lines of `data`, starting with the line `from` is in, or an
+/// empty range if none were completed at or after `from`.
+///
+/// A line longer than [`LOOKBACK`] is taken to start that far before `from`.
+#[must_use]
+pub fn complete_lines(data: &[u8], from: usize) -> Range<usize> {
+    let lookback = &data[from.saturating_sub(LOOKBACK)..from];
+    let start = lookback
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(from - lookback.len(), |i| from - lookback.len() + i + 1);
+    let end = data[start..]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(start, |i| start + i + 1);
+    start..end
+}
+
+/// Strips a `[  12.345678]` timestamp, returning it in microseconds.
+pub(crate) fn strip_kernel_time(v: &str) -> Option<(u64, &str)> {
+    let (time, rest) = v.strip_prefix('[')?.split_once(']')?;
+    let (secs, frac) = time.trim_start().split_once('.')?;
+    if secs.is_empty() || frac.is_empty() || frac.len() > 9 {
+        return None;
+    }
+    let scale = 10_u64.pow(9 - u32::try_from(frac.len()).ok()?);
+    let secs: u64 = secs.parse().ok()?;
+    let nanos: u64 = frac.parse::<u64>().ok()? * scale;
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    let time = secs.checked_mul(1_000_000)?.checked_add(nanos / 1000)?;
+    Some((time, rest.trim_start()))
+}
+
+/// Strips Lilu's `PRODUCT: %10s: ` prefix, returning the product and module.
+fn strip_lilu_prefix(v: &str) -> Option<(&str, &str, &str)> {
+    let (product, rest) = v.split_once(": ")?;
+    let (field, rest) = rest.split_once(": ")?;
+    let module = field.trim_start_matches(' ');
+    (field.len() >= 10 && is_ident(product) && is_ident(module)).then_some((product, module, rest))
+}
+
+/// Strips a `DBG:`, `[WARN]` or `(ERR)` style level tag.
+fn strip_level(v: &str) -> Option<(Level, &str)> {
+    let (tag, rest) = if let Some(v) = v.strip_prefix('[') {
+        v.split_once(']')?
+    } else if let Some(v) = v.strip_prefix('(') {
+        v.split_once(')')?
+    } else {
+        v.split_once(':')?
+    };
+    Some((Level::from_tag(tag)?, rest.trim_start()))
+}
+
+impl LogLine {
+    /// Parses a single line, which may still end with its newline.
+    #[must_use]
+    pub fn parse(offset: usize, line: &str) -> Self {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let kernel_time = strip_kernel_time(rest).map(|(time, v)| {
+            rest = v;
+            time
+        });
+        let mut level = None;
+        let (product, module) = match strip_lilu_prefix(rest) {
+            Some((product, module, v)) => {
+                rest = v;
+                if let Some(v) = rest.strip_prefix("@ ") {
+                    level = Some(Level::Debug);
+                    rest = v;
+                }
+                (Some(product.to_owned()), Some(module.to_owned()))
+            }
+            None => (None, None),
+        };
+        // Function names are only recognised after a Lilu prefix, as plain
+        // text often starts with a word and a colon too.
+        let mut function = None;
+        for _ in 0..2 {
+            if let Some((v, after)) = strip_level(rest) {
+                level = Some(level.map_or(v, |l| l.max(v)));
+                rest = after;
+            } else if let Some((name, after)) = rest
+                .split_once(": ")
+                .filter(|(v, _)| module.is_some() && function.is_none() && is_ident(v))
+            {
+                function = Some(name.to_owned());
+                rest = after;
+            }
+        }
+        Self {
+            offset,
+            len: line.len(),
+            kernel_time,
+            product,
+            module,
+            function,
+            level: level.unwrap_or(Level::Info),
+            message: rest.to_owned(),
+        }
+    }
+
+    /// Parses all lines of `data`, replacing invalid UTF-8 in them.
+    #[must_use]
+    pub fn parse_all(data: &[u8]) -> Vec<Self> {
+        let mut offset = 0;
+        data.split_inclusive(|&b| b == b'\n')
+            .map(|v| {
+                let line = Self::parse(offset, &String::from_utf8_lossy(v));
+                offset += v.len();
+                Self {
+                    len: v.len(),
+                    ..line
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_lines_start_at_the_appended_line() {
+        let data = b"one\ntwo\nthr";
+        assert_eq!(complete_lines(data, 0), 0..8);
+        assert_eq!(complete_lines(data, 5), 4..8);
+        assert_eq!(complete_lines(data, 11), 8..8);
+        let long = [b'x'; LOOKBACK * 2];
+        let mut data = long.to_vec();
+        data.push(b'\n');
+        assert_eq!(complete_lines(&data, long.len()), LOOKBACK..data.len());
+    }
+
+    #[test]
+    fn rejects_overflowing_kernel_time() {
+        assert_eq!(
+            strip_kernel_time("[   12.345678] boot"),
+            Some((12_345_678, "boot"))
+        );
+        assert_eq!(strip_kernel_time("[99999999999999999.000000] boot"), None);
+    }
+}
@@ -1,58 +1,415 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use actix_web::web;
-use sequence_generator::sequence_generator;
-use tokio::io::AsyncReadExt;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+use wred_server::protocol::{Decoder, Frame, Receipt};
 
-fn generate_id() -> (sequence_generator::SequenceProperties, u64) {
-    let properties = wred_server::get_id_props();
-    let id = sequence_generator::generate_id(&properties).unwrap();
-    (properties, id)
+use crate::ingest::{Cursor, Ingest, Source};
+
+/// How long to wait before accepting again after it failed.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Time a TLS handshake may take, which is much shorter than senders may go
+/// idle for, so stalled handshakes don't hold connection slots.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open connection counted towards the per-IP limit until dropped.
+struct ConnectionSlot {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    fn acquire(
+        counts: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+        ip: IpAddr,
+        max: usize,
+    ) -> Option<Self> {
+        let mut map = counts.lock().unwrap();
+        let count = map.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        drop(map);
+        Some(Self {
+            counts: Arc::clone(counts),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut map = self.counts.lock().unwrap();
+        if let Some(count) = map.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.ip);
+            }
+        }
+    }
+}
+
+struct Connection {
+    state: web::Data<crate::state::AppState>,
+    source: Source,
+    cursor: Option<Cursor>,
+    receipts: Vec<Receipt>,
+    /// Data bytes received so far.
+    received: usize,
+    max_bytes: usize,
+}
+
+impl Connection {
+    async fn append(&mut self, data: Vec<u8>) {
+        self.receipts.extend(
+            self.state
+                .ingest
+                .append(&mut self.cursor, &self.source, data)
+                .await,
+        );
+    }
+
+    /// Ends the current segment, so further data opens or finds an entry again.
+    async fn disconnect(&mut self) {
+        if let Some(cursor) = self.cursor.take() {
+            self.state.ingest.disconnect(cursor).await;
+        }
+    }
+
+    /// Returns whether the connection should be kept open.
+    async fn handle_frame(&mut self, frame: Frame) -> bool {
+        match frame {
+            Frame::Hello { session, device_id } => {
+                self.source.session = Some(session);
+                self.source.device_id = device_id;
+                self.disconnect().await;
+            }
+            Frame::Auth { token } => match self.state.tokens.authenticate(Some(&token)) {
+                Ok(device) => {
+                    if device.is_none() {
+                        println!("Unknown ingest token from {}", self.source.addr.ip());
+                    }
+                    self.source.device = device;
+                    self.disconnect().await;
+                }
+                Err(e) => {
+                    println!("Closing connection from {}: {e}", self.source.addr.ip());
+                    return false;
+                }
+            },
+            Frame::Data(_) if self.source.device.is_none() && self.state.tokens.is_strict() => {
+                println!(
+                    "Closing connection from {}: no ingest token",
+                    self.source.addr.ip()
+                );
+                return false;
+            }
+            Frame::Data(v) => {
+                let allowed = self.max_bytes.saturating_sub(self.received);
+                self.received += v.len();
+                if v.len() > allowed {
+                    let mut v = v;
+                    v.truncate(allowed);
+                    v.extend(
+                        crate::ingest::truncation_marker(&format!(
+                            "connection exceeded {} bytes",
+                            self.max_bytes
+                        ))
+                        .bytes(),
+                    );
+                    self.append(v).await;
+                    println!(
+                        "Closing connection from {}: too much data",
+                        self.source.addr.ip()
+                    );
+                    return false;
+                }
+                self.append(v).await;
+            }
+            Frame::End => {
+                if let Some(cursor) = self.cursor {
+                    self.state
+                        .ingest
+                        .update(cursor.id, |ent| ent.is_ended = true)
+                        .await;
+                }
+                return false;
+            }
+        }
+        true
+    }
 }
 
 async fn handle_connection(
-    logs: &mut HashMap<u64, wred_server::LogEntry>,
-    mut stream: tokio::net::TcpStream,
+    state: web::Data<crate::state::AppState>,
+    stream: impl AsyncRead + AsyncWrite + Send,
     addr: std::net::SocketAddr,
 ) {
-    stream.readable().await.unwrap();
     println!("Incoming connection from: {}", addr.ip());
 
-    let mut buf = Vec::new();
-    while let Ok(n) = stream.read_buf(&mut buf).await {
-        if n == 0 {
-            break;
+    let limits = &state.config.limits;
+    let mut conn = Connection {
+        state: state.clone(),
+        source: Source::new(addr),
+        cursor: None,
+        receipts: Vec::new(),
+        received: 0,
+        max_bytes: limits.max_connection_bytes,
+    };
+    let mut decoder = Decoder::default();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = vec![0; 64 * 1024];
+    let idle_timeout = Duration::from_secs(limits.idle_timeout_secs);
+    let deadline = Instant::now() + Duration::from_secs(limits.total_timeout_secs);
+    'read: loop {
+        let timeout = deadline.min(Instant::now() + idle_timeout);
+        let n = match tokio::time::timeout_at(timeout, reader.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            Ok(_) => break,
+            Err(_) => {
+                let reason = if timeout == deadline {
+                    "connection time limit"
+                } else {
+                    "idle timeout"
+                };
+                println!("Closing connection from {}: {reason}", addr.ip());
+                break;
+            }
+        };
+        let frames = match decoder.feed(&buf[..n]) {
+            Ok(v) => v,
+            Err(e) => {
+                println!("Dropping connection from {}: {e}", addr.ip());
+                break;
+            }
+        };
+        for frame in frames {
+            let keep_open = conn.handle_frame(frame).await;
+            for receipt in conn.receipts.drain(..) {
+                // Legacy senders may not read at all, so failing to write is fine.
+                let _e = if decoder.is_framed() {
+                    writer.write_all(&receipt.encode()).await
+                } else {
+                    writer.write_all(format!("{receipt}\n").as_bytes()).await
+                };
+            }
+            if !keep_open {
+                break 'read;
+            }
         }
     }
+    if let Some(frame) = decoder.finish() {
+        conn.handle_frame(frame).await;
+    }
+    conn.disconnect().await;
 
-    let (properties, id) = generate_id();
-    let v = wred_server::LogEntry {
-        last_updated: sequence_generator::decode_id_unix_epoch_micros(id, &properties),
-        addr,
-        data: String::from_utf8_lossy(&buf).to_string(),
-    };
-    println!("{v:#?}");
+    println!("Connection from {} closed", addr.ip());
+}
 
-    if let Some((_, ent)) = logs
-        .iter_mut()
-        .find(|(_, e)| e.addr.ip() == addr.ip() && v.last_updated - e.last_updated < 60_000_000)
-    {
-        ent.last_updated = v.last_updated;
-        ent.data += &v.data;
-    } else {
-        logs.insert(id, v);
+fn handle_syslog(
+    ent: &mut wred_server::LogEntry,
+    hostname: Option<String>,
+    app_name: Option<String>,
+    severity: u8,
+) {
+    if let Some(hostname) = hostname {
+        ent.metadata.insert("syslog.hostname".to_owned(), hostname);
+    }
+    if let Some(app_name) = app_name {
+        ent.metadata.insert("syslog.app_name".to_owned(), app_name);
+    }
+    // Keep the most severe level seen so far.
+    let prev = ent
+        .metadata
+        .get("syslog.severity")
+        .and_then(|v| crate::syslog::SEVERITIES.iter().position(|s| s == v));
+    if !matches!(prev, Some(v) if v <= usize::from(severity)) {
+        ent.metadata.insert(
+            "syslog.severity".to_owned(),
+            crate::syslog::SEVERITIES[usize::from(severity)].to_owned(),
+        );
+    }
+}
+
+async fn handle_datagram(
+    ingest: &Ingest,
+    cursor: &mut Option<Cursor>,
+    data: &[u8],
+    addr: std::net::SocketAddr,
+    is_syslog: bool,
+) {
+    let source = Source::new(addr);
+    // Messages that aren't valid UTF-8 are kept as is rather than parsed.
+    let msg = std::str::from_utf8(data)
+        .ok()
+        .filter(|_| is_syslog)
+        .and_then(crate::syslog::parse);
+    match msg {
+        Some(msg) => {
+            let mut line = msg.msg.to_owned();
+            if !line.ends_with('\n') {
+                line.push('\n');
+            }
+            let hostname = msg.hostname.map(str::to_owned);
+            let app_name = msg.app_name.map(str::to_owned);
+            let severity = msg.severity;
+            ingest.append(cursor, &source, line.into_bytes()).await;
+            if let Some(cursor) = *cursor {
+                ingest
+                    .update(cursor.id, move |ent| {
+                        handle_syslog(ent, hostname, app_name, severity);
+                    })
+                    .await;
+            }
+        }
+        None => {
+            ingest.append(cursor, &source, data.to_vec()).await;
+        }
+    }
+}
+
+/// Disconnects the segments of senders that haven't sent anything for
+/// `window`, as datagrams never close a connection.
+async fn close_quiet(
+    ingest: &Ingest,
+    cursors: &mut HashMap<SocketAddr, (Cursor, Instant)>,
+    window: Duration,
+) {
+    let quiet: Vec<_> = cursors
+        .iter()
+        .filter(|(_, (_, last))| last.elapsed() >= window)
+        .map(|(&addr, _)| addr)
+        .collect();
+    for addr in quiet {
+        if let Some((cursor, _)) = cursors.remove(&addr) {
+            ingest.disconnect(cursor).await;
+        }
     }
 }
 
+fn start_datagram_receiver(state: &web::Data<crate::state::AppState>, port: u16, is_syslog: bool) {
+    let bind = (state.config.ip.clone(), port);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let socket = tokio::net::UdpSocket::bind(bind).await.unwrap();
+        let mut buf = vec![0; 65536];
+        let window = Duration::from_micros(crate::ingest::MERGE_WINDOW);
+        let mut cursors = HashMap::new();
+        let mut sweep = tokio::time::interval(window);
+        loop {
+            let res = tokio::select! {
+                res = socket.recv_from(&mut buf) => res,
+                _ = sweep.tick() => {
+                    close_quiet(&state.ingest, &mut cursors, window).await;
+                    continue;
+                }
+            };
+            let Ok((n, addr)) = res else {
+                continue;
+            };
+            if !state
+                .rejections
+                .check(&state.config.logger_access, addr.ip(), false)
+            {
+                continue;
+            }
+            let mut cursor = match cursors.remove(&addr) {
+                Some((v, last)) if last.elapsed() < window => Some(v),
+                Some((v, _)) => {
+                    state.ingest.disconnect(v).await;
+                    None
+                }
+                None => None,
+            };
+            handle_datagram(&state.ingest, &mut cursor, &buf[..n], addr, is_syslog).await;
+            if let Some(cursor) = cursor {
+                cursors.insert(addr, (cursor, Instant::now()));
+            }
+        }
+    });
+}
+
 pub fn start_log_receiver(state: web::Data<crate::state::AppState>) {
+    let datagram_ports = [
+        (state.config.udp_port, false),
+        (state.config.syslog_port, true),
+    ];
+    for (port, is_syslog) in datagram_ports {
+        let Some(port) = port else {
+            continue;
+        };
+        if state.tokens.is_strict() {
+            // Datagrams have no way to present a token.
+            println!("Not listening on UDP port {port}: strict_tokens is set");
+        } else {
+            start_datagram_receiver(&state, port, is_syslog);
+        }
+    }
+
     let bind = (state.config.ip.clone(), state.config.logger_port);
+    let tls = state
+        .tls
+        .clone()
+        .filter(|_| state.config.tls.as_ref().is_some_and(|v| v.logger))
+        .map(tokio_rustls::TlsAcceptor::from);
     tokio::spawn(async move {
         let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+        let counts = Arc::default();
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
-            let log_entries = Arc::clone(&state.logs);
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    // E.g. out of file descriptors, which takes a moment to
+                    // clear up.
+                    println!("Failed to accept a connection: {e}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            if !state
+                .rejections
+                .check(&state.config.logger_access, addr.ip(), false)
+            {
+                continue;
+            }
+            let Some(slot) = ConnectionSlot::acquire(
+                &counts,
+                addr.ip(),
+                state.config.limits.max_connections_per_ip,
+            ) else {
+                println!(
+                    "Rejecting connection from {}: too many connections",
+                    addr.ip()
+                );
+                continue;
+            };
+            let state = state.clone();
+            let acceptor = tls.clone();
             tokio::spawn(async move {
-                handle_connection(&mut *log_entries.lock().await, stream, addr).await;
+                match acceptor {
+                    Some(acceptor) => {
+                        let handshake =
+                            tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                        match handshake.await {
+                            Ok(Ok(stream)) => handle_connection(state, stream, addr).await,
+                            Ok(Err(e)) => {
+                                println!("TLS handshake with {} failed: {e}", addr.ip());
+                            }
+                            Err(_) => println!("TLS handshake with {} timed out", addr.ip()),
+                        }
+                    }
+                    None => handle_connection(state, stream, addr).await,
+                }
+                drop(slot);
             });
         }
     });
@@ -0,0 +1,174 @@
+//! All entries as the API sees them, whether held in memory or only saved.
+//!
+//! Reads merge the entries held by the ingest task with the store. Writes go
+//! through the ingest task, which keeps the store up to date with the saved
+//! entries it holds, and only fall back to the store for the others.
+
+use std::{
+    collections::HashSet,
+    io::{Error, Result},
+    sync::Arc,
+};
+
+use wred_server::{
+    store::{Listed, LogStore},
+    LogEntry, LogEntryPartial,
+};
+
+use crate::{
+    ingest::{Ingest, Live},
+    rules::Labeler,
+    symbols::Symbols,
+};
+
+#[derive(Debug)]
+pub struct Logs {
+    live: Live,
+    store: Arc<dyn LogStore>,
+    ingest: Ingest,
+    symbols: Arc<Symbols>,
+}
+
+impl Logs {
+    pub const fn new(
+        live: Live,
+        store: Arc<dyn LogStore>,
+        ingest: Ingest,
+        symbols: Arc<Symbols>,
+    ) -> Self {
+        Self {
+            live,
+            store,
+            ingest,
+            symbols,
+        }
+    }
+
+    /// Runs `f` on the store on a blocking thread, so async workers aren't held up
+    /// by its I/O.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&dyn LogStore) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let store = Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(store.as_ref()))
+            .await
+            .map_err(Error::other)?
+    }
+
+    /// Lists the entries whose data contains `needle`, all of them if it's
+    /// empty. Entries held in memory are listed as they are there.
+    pub async fn list(&self, needle: &[u8]) -> Result<Vec<Listed>> {
+        let search = needle.to_vec();
+        let (mut listed, found) = self
+            .blocking(move |store| {
+                let found = if search.is_empty() {
+                    None
+                } else {
+                    Some(store.search(&search)?.into_iter().collect::<HashSet<_>>())
+                };
+                Ok((store.list()?, found))
+            })
+            .await?;
+        let saved: HashSet<_> = listed.iter().map(|v| v.partial.id).collect();
+        if let Some(found) = found {
+            listed.retain(|v| found.contains(&v.partial.id));
+        }
+        let live = self.live.read().await;
+        let mut resp: Vec<_> = live
+            .iter()
+            .filter(|(_, v)| v.contains(needle))
+            .map(|(&id, v)| Listed {
+                partial: LogEntryPartial {
+                    is_saved: saved.contains(&id),
+                    ..v.partial(id)
+                },
+                is_ended: v.is_ended,
+                size: v.data.len() as u64,
+            })
+            .collect();
+        resp.extend(
+            listed
+                .into_iter()
+                .filter(|v| !live.contains_key(&v.partial.id)),
+        );
+        Ok(resp)
+    }
+
+    /// Lists the saved entries only.
+    pub async fn saved(&self) -> Result<Vec<Listed>> {
+        self.blocking(|store| store.list()).await
+    }
+
+    /// Runs `f` on a copy of the entry `id`, so entries in memory aren't kept
+    /// locked against ingest while it runs.
+    pub async fn with_entry<R>(
+        &self,
+        id: u64,
+        f: impl FnOnce(&LogEntry) -> R,
+    ) -> Result<Option<R>> {
+        let live = self.live.read().await.get(&id).cloned();
+        if let Some(v) = live {
+            return Ok(Some(f(&v)));
+        }
+        let stored = self.blocking(move |store| store.get(id)).await?;
+        Ok(stored.map(|mut v| {
+            self.symbols.symbolicate(&mut v.panics);
+            f(&v)
+        }))
+    }
+
+    /// Saves the entry `id`, returning whether there is one.
+    pub async fn save(&self, id: u64) -> Result<bool> {
+        self.ingest.save(id).await
+    }
+
+    /// Deletes the entry `id`, returning it if there was one.
+    pub async fn delete(&self, id: u64) -> Result<Option<LogEntry>> {
+        let removed = self.ingest.delete(id).await;
+        self.blocking(move |store| {
+            let removed = match removed {
+                Some(v) => Some(v),
+                None => store.get(id)?,
+            };
+            store.delete(id)?;
+            Ok(removed)
+        })
+        .await
+    }
+
+    /// Relabels all entries with the rules in `labeler`.
+    pub async fn relabel(&self, labeler: &Arc<Labeler>) -> Result<()> {
+        let live = self
+            .ingest
+            .update_all({
+                let labeler = Arc::clone(labeler);
+                move |ent| labeler.relabel(ent)
+            })
+            .await;
+        let labeler = Arc::clone(labeler);
+        self.blocking(move |store| {
+            for v in store.list()? {
+                let id = v.partial.id;
+                if live.contains(&id) {
+                    continue;
+                }
+                if let Some(mut ent) = store.get(id)? {
+                    labeler.relabel(&mut ent);
+                    store.update(id, &ent)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Brings the backtraces of the entries in memory up to date with the
+    /// symbols. Saved ones are symbolicated as they are read.
+    pub async fn symbolicate(&self) {
+        let symbols = Arc::clone(&self.symbols);
+        self.ingest
+            .update_all(move |ent| symbols.symbolicate(&mut ent.panics))
+            .await;
+    }
+}
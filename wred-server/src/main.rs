@@ -8,42 +8,203 @@
 )]
 #![allow(clippy::module_name_repetitions)]
 
-use actix_web::{web, App, HttpServer};
+use std::{collections::HashMap, sync::Arc};
 
+use actix_web::{
+    dev::{Service, ServiceResponse},
+    web, App, HttpResponse, HttpServer,
+};
+use tokio::sync::RwLock;
+
+mod access;
+mod classify;
+mod clusters;
+mod db;
+mod extract;
+mod files;
+mod format;
+mod ingest;
+mod journal;
 mod log_service;
+mod logs;
+mod migrate;
+mod retention;
 mod routes;
+mod rules;
 mod state;
+mod symbols;
+mod syslog;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod tokens;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let f = std::fs::File::open("./config.ron").expect("Failed opening config");
     let config: state::ServerConfig = ron::de::from_reader(f).unwrap();
-    let state = web::Data::new(state::AppState {
-        config,
-        ..Default::default()
+    let _e = std::fs::create_dir_all(&config.log_dir);
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        return migrate::run(&config);
+    }
+    let extractor = extract::Extractor::new(&config.metadata_rules).expect("Invalid metadata rule");
+    let symbols = Arc::new(symbols::Symbols::load());
+    let classifier =
+        classify::Classifier::new(&config.outcome_rules).expect("Invalid outcome rule");
+    let labeler = Arc::new(rules::Labeler::load());
+    let templates_path = config.log_dir.join(clusters::FILE_NAME);
+    let templates = Arc::new(
+        clusters::Templates::load(&templates_path).unwrap_or_else(|e| {
+            println!("Failed loading template counts, counting them again: {e}");
+            clusters::Templates::default()
+        }),
+    );
+    let store = config
+        .store
+        .open(&config.log_dir)
+        .expect("Failed opening saved logs");
+    if !matches!(config.store, state::StoreKind::Memory) {
+        match files::import_legacy(store.as_ref(), &config.log_dir) {
+            Ok(0) => {}
+            Ok(n) => println!("Imported {n} saved logs"),
+            Err(e) => println!("Failed importing saved logs: {e}"),
+        }
+    }
+    // Counts of unsaved entries are dropped before the journal is replayed,
+    // and saved entries changed since the counts were saved are counted again
+    // in the background, so startup doesn't wait for them to be read.
+    let recount = match store.list() {
+        Ok(v) => templates.retain_stored(&v),
+        Err(e) => {
+            println!("Failed listing saved logs: {e}");
+            Vec::new()
+        }
+    };
+    tokio::task::spawn_blocking({
+        let store = Arc::clone(&store);
+        let templates = Arc::clone(&templates);
+        move || {
+            for id in recount {
+                if let Ok(Some(ent)) = store.get(id) {
+                    templates.apply(id, &ent, 0);
+                }
+            }
+        }
     });
-    let _e = std::fs::create_dir_all(&state.config.log_dir);
-    for ent in std::fs::read_dir(&state.config.log_dir).unwrap() {
-        let path = ent.unwrap().path();
-        if path.is_file() {
-            let data = std::fs::read(path).unwrap();
-            let ent: (u64, wred_server::LogEntry) = postcard::from_bytes(&data).unwrap();
-            state.logs.lock().await.insert(ent.0, ent.1);
+    tokio::spawn({
+        let templates = Arc::clone(&templates);
+        let path = templates_path.clone();
+        async move {
+            let mut interval = tokio::time::interval(clusters::SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let (templates, path) = (Arc::clone(&templates), path.clone());
+                let res = tokio::task::spawn_blocking(move || templates.save(&path)).await;
+                if let Ok(Err(e)) = res {
+                    println!("Failed saving template counts: {e}");
+                }
+            }
         }
+    });
+    let analysis = ingest::Analysis {
+        extractor,
+        symbols: Arc::clone(&symbols),
+        classifier: classifier.clone(),
+        labeler: Arc::clone(&labeler),
+        templates: Arc::clone(&templates),
+    };
+    let (mut journal, records) = journal::Journal::open(&config.log_dir.join(journal::FILE_NAME))
+        .expect("Failed opening journal");
+    let mut logs = HashMap::new();
+    ingest::replay(&mut logs, records, store.as_ref(), &analysis);
+    journal.compact(&logs).expect("Failed compacting journal");
+    if !logs.is_empty() {
+        println!("Recovered {} unsaved logs from the journal", logs.len());
     }
+    let live = Arc::new(RwLock::new(logs));
+    let evictions = Arc::new(retention::Evictions::default());
+    let ingest = ingest::Ingest::spawn(
+        Arc::clone(&live),
+        Arc::clone(&store),
+        journal,
+        config.limits.max_unsaved_bytes,
+        Arc::clone(&evictions),
+        analysis,
+    );
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|v| tls::server_config(v).expect("Failed loading TLS certificate"));
+    let state = web::Data::new(state::AppState {
+        logs: logs::Logs::new(live, store, ingest.clone(), Arc::clone(&symbols)),
+        ingest,
+        tokens: tokens::Tokens::load(&config),
+        symbols,
+        classifier,
+        labeler,
+        templates,
+        config,
+        rejections: access::Rejections::default(),
+        evictions,
+        tls,
+    });
 
     log_service::start_log_receiver(state.clone());
+    retention::start(state.clone());
+    let templates = Arc::clone(&state.templates);
     let bind = (state.config.ip.clone(), state.config.api_port);
-    HttpServer::new(move || {
+    let tls = state.tls.clone();
+    let server = HttpServer::new(move || {
+        let access_state = state.clone();
         App::new()
+            .wrap_fn(move |req, srv| {
+                let permitted = req.peer_addr().is_some_and(|v| {
+                    access_state
+                        .rejections
+                        .check(&access_state.config.api_access, v.ip(), true)
+                });
+                let resp = if permitted {
+                    Ok(srv.call(req))
+                } else {
+                    Err(req.into_response(HttpResponse::Forbidden().finish()))
+                };
+                async move {
+                    match resp {
+                        Ok(fut) => fut.await.map(ServiceResponse::map_into_left_body),
+                        Err(resp) => Ok(resp.map_into_right_body()),
+                    }
+                }
+            })
             .app_data(state.clone())
+            .app_data(web::PayloadConfig::new(
+                state.config.limits.max_connection_bytes,
+            ))
             .service(routes::get_logs)
             .service(routes::get_log)
+            .service(routes::get_log_raw)
+            .service(routes::get_log_lines)
+            .service(routes::get_log_templates)
+            .service(routes::get_diff)
             .service(routes::delete_log)
             .service(routes::save_log)
+            .service(routes::ingest)
+            .service(routes::create_token)
+            .service(routes::revoke_token)
+            .service(routes::upload_symbols)
+            .service(routes::delete_symbols)
+            .service(routes::reload_rules)
+            .service(routes::get_evictions)
             .service(actix_files::Files::new("/", "./dist").index_file("index.html"))
-    })
-    .bind(bind)?
+    });
+    let res = match tls {
+        Some(tls) => server.bind_rustls_0_23(bind, (*tls).clone())?,
+        None => server.bind(bind)?,
+    }
     .run()
-    .await
+    .await;
+    if let Err(e) = templates.save(&templates_path) {
+        println!("Failed saving template counts: {e}");
+    }
+    res
 }
@@ -0,0 +1,56 @@
+//! `wred-server migrate`, which rewrites all saved and unsaved logs in the
+//! current format.
+//!
+//! Logs in older formats are read as they are anyway, but only in memory, and
+//! fields added since are filled in by analysing them again.
+
+use std::{collections::HashMap, io::Error, sync::Arc};
+
+use crate::{
+    classify::Classifier,
+    extract::Extractor,
+    files, format, ingest,
+    journal::{self, Journal},
+    rules::Labeler,
+    state::{ServerConfig, StoreKind},
+    symbols::Symbols,
+};
+
+pub fn run(config: &ServerConfig) -> std::io::Result<()> {
+    let store = config.store.open(&config.log_dir)?;
+    let analysis = ingest::Analysis {
+        extractor: Extractor::new(&config.metadata_rules).map_err(Error::other)?,
+        symbols: Arc::new(Symbols::load()),
+        classifier: Classifier::new(&config.outcome_rules).map_err(Error::other)?,
+        labeler: Arc::new(Labeler::load()),
+        templates: Arc::default(),
+    };
+
+    let imported = if matches!(config.store, StoreKind::Memory) {
+        0
+    } else {
+        files::import_legacy(store.as_ref(), &config.log_dir)?
+    };
+    let mut saved = 0;
+    for v in store.list()? {
+        let id = v.partial.id;
+        if let Some(mut ent) = store.get(id)? {
+            analysis.reapply(id, &mut ent);
+            store.save(id, &ent)?;
+            saved += 1;
+        }
+    }
+
+    let (mut journal, records) = Journal::open(&config.log_dir.join(journal::FILE_NAME))?;
+    let mut logs = HashMap::new();
+    ingest::replay(&mut logs, records, store.as_ref(), &analysis);
+    journal.compact(&logs)?;
+
+    println!(
+        "Imported {imported} logs and rewrote {saved} saved and {} unsaved logs in format \
+         version {}",
+        logs.len(),
+        format::VERSION
+    );
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! Detection of kernel panics in log data.
+//!
+//! macOS prints panics as a block starting with `panic(cpu N caller 0x...)`,
+//! followed by registers, the backtrace and the kexts in it:
+//!
+//! ```text
+//! panic(cpu 2 caller 0xffffff801a2b3c4d): Kernel trap at 0x..., type 14=page fault
+//! Backtrace (CPU 2), Frame : Return Address
+//! 0xffffffa0b1c8b9f0 : 0xffffff801a2b3c4d mach_kernel : _handle_debugger_trap + 0x4ad
+//!       Kernel Extensions in backtrace:
+//!          com.nyancat.whateverred(1.0.2)[9C3B...]@0xffffff7f9a000000->0xffffff7f9a0fffff
+//!             dependency: as.vit9696.Lilu(1.6.3)[5D2E...]@0xffffff7f99000000->0xffffff7f990fffff
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+/// Lines after which a panic block without an end is cut off.
+const MAX_LINES: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    pub frame: u64,
+    pub return_address: u64,
+    /// Symbol printed with `keepsyms=1`, e.g. `mach_kernel : _panic + 0x4ad`.
+    pub symbol: Option<String>,
+    /// Function and offset from uploaded kext symbols.
+    pub resolved: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Kext {
+    pub name: String,
+    pub version: String,
+    pub uuid: Option<String>,
+    /// Load address range.
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    /// Whether it's only listed as a dependency of a kext in the backtrace.
+    pub is_dependency: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PanicReport {
+    /// Offset of the block in the entry's data.
+    pub offset: usize,
+    /// Length of the block, including its last newline.
+    pub len: usize,
+    pub cpu: Option<u32>,
+    pub caller: Option<u64>,
+    /// Text after `panic(...): `, without the quotes and source location
+    /// around it if any.
+    pub message: String,
+    pub backtrace: Vec<Frame>,
+    pub kexts: Vec<Kext>,
+    /// Process running on the panicked thread.
+    pub process: Option<String>,
+    /// Whether the end of the block was received.
+    pub is_complete: bool,
+}
+
+fn hex(v: &str) -> Option<u64> {
+    u64::from_str_radix(v.trim().strip_prefix("0x")?, 16).ok()
+}
+
+/// Parses a `0xFRAME : 0xRETURN [symbol]` backtrace line.
+fn parse_frame(line: &str) -> Option<Frame> {
+    let (frame, rest) = line.split_once(" : ")?;
+    let (return_address, symbol) = rest.split_once(' ').unwrap_or((rest, ""));
+    let symbol = symbol.trim();
+    Some(Frame {
+        frame: hex(frame)?,
+        return_address: hex(return_address)?,
+        symbol: (!symbol.is_empty()).then(|| symbol.to_owned()),
+        resolved: None,
+    })
+}
+
+/// Parses a `name(version)[uuid]@0xSTART->0xEND` kext line.
+fn parse_kext(line: &str) -> Option<Kext> {
+    let (line, is_dependency) = line
+        .strip_prefix("dependency:")
+        .map_or((line, false), |v| (v.trim_start(), true));
+    let (name, rest) = line.split_once('(')?;
+    let (version, rest) = rest.split_once(')')?;
+    if name.is_empty() || name.contains(char::is_whitespace) || !name.contains('.') {
+        return None;
+    }
+    let (uuid, rest) = rest
+        .strip_prefix('[')
+        .and_then(|v| v.split_once(']'))
+        .map_or((None, rest), |(uuid, rest)| (Some(uuid.to_owned()), rest));
+    let (start, end) = rest.strip_prefix('@').map_or((None, None), |v| {
+        v.split_once("->")
+            .map_or_else(|| (hex(v), None), |(start, end)| (hex(start), hex(end)))
+    });
+    Some(Kext {
+        name: name.to_owned(),
+        version: version.to_owned(),
+        uuid,
+        start,
+        end,
+        is_dependency,
+    })
+}
+
+impl PanicReport {
+    /// Starts a report if `line` is the first line of a panic block, which
+    /// starts with `panic(cpu `, so lines merely mentioning one are skipped.
+    fn start(offset: usize, line: &str) -> Option<Self> {
+        let (head, message) = line.strip_prefix("panic(")?.split_once("):")?;
+        if !head.starts_with("cpu ") {
+            return None;
+        }
+        let message = message.trim();
+        let mut cpu = None;
+        let mut caller = None;
+        let mut words = head.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "cpu" => cpu = words.next().and_then(|v| v.parse().ok()),
+                "caller" => caller = words.next().and_then(hex),
+                _ => {}
+            }
+        }
+        Some(Self {
+            offset,
+            len: 0,
+            cpu,
+            caller,
+            message: message
+                .strip_prefix('"')
+                .and_then(|v| v.rsplit_once('"'))
+                .map_or(message, |(v, _)| v)
+                .to_owned(),
+            backtrace: Vec::new(),
+            kexts: Vec::new(),
+            process: None,
+            is_complete: false,
+        })
+    }
+
+    /// Adds a line of the block, returning whether it ended it.
+    fn add_line(&mut self, line: &str) -> bool {
+        if let Some(frame) = parse_frame(line) {
+            self.backtrace.push(frame);
+        } else if let Some(kext) = parse_kext(line) {
+            self.kexts.push(kext);
+        } else if let Some(v) = line.strip_prefix("Process name corresponding to current thread:") {
+            self.process = Some(v.trim().to_owned());
+        } else if line.starts_with("System uptime in nanoseconds") {
+            return true;
+        }
+        false
+    }
+
+    /// Finds all panic blocks in the complete lines of `data`.
+    #[must_use]
+    pub fn parse_all(data: &[u8]) -> Vec<Self> {
+        let mut panics = Vec::new();
+        scan(&mut panics, data, 0);
+        panics
+    }
+}
+
+/// Updates `panics` for data appended to `data` at `from`, continuing the last
+/// report if it was still being received.
+///
+/// Only complete lines are looked at, so a line still being received is looked
+/// at again once the rest of it arrives.
+pub fn scan(panics: &mut Vec<PanicReport>, data: &[u8], from: usize) {
+    let lines = crate::line::complete_lines(data, from);
+    if lines.is_empty() {
+        return;
+    }
+    let (mut start, end) = (lines.start, lines.end);
+    // The report ends with the last complete line before the appended one.
+    if let Some(last) = panics.last() {
+        if !last.is_complete && last.offset + last.len == lines.start {
+            start = last.offset;
+            panics.pop();
+        }
+    }
+
+    let mut current: Option<(PanicReport, usize)> = None;
+    let mut offset = start;
+    for line in data[start..end].split_inclusive(|&b| b == b'\n') {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        let text = crate::line::strip_kernel_time(text).map_or(text, |(_, v)| v);
+        let text = text.trim();
+        if let Some(report) = PanicReport::start(offset, text) {
+            panics.extend(current.take().map(|(v, _)| v));
+            current = Some((report, 0));
+        }
+        if let Some((report, lines)) = &mut current {
+            report.len = offset + line.len() - report.offset;
+            *lines += 1;
+            if *lines > 1 && report.add_line(text) {
+                report.is_complete = true;
+            }
+            if report.is_complete || *lines >= MAX_LINES {
+                panics.extend(current.take().map(|(v, _)| v));
+            }
+        }
+        offset += line.len();
+    }
+    panics.extend(current.map(|(v, _)| v));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG: &str = "\
+[   10.000000]: boot\n\
+[   12.345678]: panic(cpu 2 caller 0xffffff801a2b3c4d): \"WhateverRed: bad state\"@/src/kern.c:42\n\
+Backtrace (CPU 2), Frame : Return Address\n\
+0xffffffa0b1c8b9f0 : 0xffffff801a2b3c4d mach_kernel : _handle_debugger_trap + 0x4ad\n\
+0xffffffa0b1c8ba40 : 0xffffff7f9a001234\n\
+      Kernel Extensions in backtrace:\n\
+         com.nyancat.whateverred(1.0.2)[9C3B]@0xffffff7f9a000000->0xffffff7f9a0fffff\n\
+            dependency: as.vit9696.Lilu(1.6.3)[5D2E]@0xffffff7f99000000->0xffffff7f990fffff\n\
+\n\
+Process name corresponding to current thread: kernel_task\n\
+System uptime in nanoseconds: 12345678901\n\
+[   13.000000]: after\n";
+
+    #[test]
+    fn parses_a_panic_log() {
+        let panics = PanicReport::parse_all(LOG.as_bytes());
+        assert_eq!(panics.len(), 1);
+        let report = &panics[0];
+        assert_eq!(report.offset, LOG.find("[   12").unwrap());
+        assert_eq!(report.offset + report.len, LOG.find("[   13").unwrap());
+        assert_eq!(report.cpu, Some(2));
+        assert_eq!(report.caller, Some(0xFFFF_FF80_1A2B_3C4D));
+        assert_eq!(report.message, "WhateverRed: bad state");
+        assert_eq!(report.backtrace.len(), 2);
+        assert_eq!(
+            report.backtrace[0].symbol.as_deref(),
+            Some("mach_kernel : _handle_debugger_trap + 0x4ad")
+        );
+        assert_eq!(report.backtrace[1].return_address, 0xFFFF_FF7F_9A00_1234);
+        assert_eq!(report.backtrace[1].symbol, None);
+        let kexts: Vec<_> = report
+            .kexts
+            .iter()
+            .map(|v| (v.name.as_str(), v.is_dependency))
+            .collect();
+        assert_eq!(
+            kexts,
+            [
+                ("com.nyancat.whateverred", false),
+                ("as.vit9696.Lilu", true)
+            ]
+        );
+        assert_eq!(report.process.as_deref(), Some("kernel_task"));
+        assert!(report.is_complete);
+    }
+
+    #[test]
+    fn continues_a_report_across_scans() {
+        let data = LOG.as_bytes();
+        // Cut in the middle of a line, as data arrives.
+        let split = LOG.find("dependency").unwrap() + 5;
+        let mut panics = Vec::new();
+        scan(&mut panics, &data[..split], 0);
+        assert_eq!(panics.len(), 1);
+        assert!(!panics[0].is_complete);
+        assert_eq!(panics[0].kexts.len(), 1);
+
+        scan(&mut panics, data, split);
+        assert_eq!(panics, PanicReport::parse_all(data));
+    }
+
+    #[test]
+    fn parses_kext_lines() {
+        let kext = parse_kext(
+            "dependency: as.vit9696.Lilu(1.6.3)[5D2E]@0xffffff7f99000000->0xffffff7f990fffff",
+        )
+        .unwrap();
+        assert_eq!(
+            kext,
+            Kext {
+                name: "as.vit9696.Lilu".to_owned(),
+                version: "1.6.3".to_owned(),
+                uuid: Some("5D2E".to_owned()),
+                start: Some(0xFFFF_FF7F_9900_0000),
+                end: Some(0xFFFF_FF7F_990F_FFFF),
+                is_dependency: true,
+            }
+        );
+        let kext = parse_kext("com.apple.iokit.IOPCIFamily(2.9)").unwrap();
+        assert_eq!((kext.uuid, kext.start, kext.end), (None, None, None));
+        assert!(parse_kext("Backtrace (CPU 2), Frame : Return Address").is_none());
+        assert!(parse_kext("Lilu(1.6.3)").is_none());
+    }
+
+    #[test]
+    fn ignores_lines_mentioning_panics() {
+        let log = "\
+WhateverRed: init: would panic(cpu 0 caller 0x1): on failure\n\
+0xffffffa0b1c8b9f0 : 0xffffff801a2b3c4d\n\
+System uptime in nanoseconds: 1\n";
+        assert!(PanicReport::parse_all(log.as_bytes()).is_empty());
+    }
+}
@@ -0,0 +1,367 @@
+//! Framed ingest protocol for the logger port.
+//!
+//! Legacy senders write raw log text and close the socket. Framed senders
+//! start with [`MAGIC`] followed by a version byte, then send frames made of
+//! a kind byte, a big-endian `u32` payload length and the payload.
+//!
+//! Once the server knows which entry the data belongs to it writes back a
+//! [`Receipt`], as a frame to framed senders and as a text line to legacy ones.
+
+use uuid::Uuid;
+
+pub const MAGIC: &[u8; 4] = b"WRED";
+pub const VERSION: u8 = 1;
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+
+const HEADER_LEN: usize = 5;
+
+const KIND_HELLO: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_END: u8 = 3;
+const KIND_AUTH: u8 = 4;
+const KIND_RECEIPT: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Identifies the boot session and the device sending it.
+    Hello {
+        session: Uuid,
+        device_id: Option<String>,
+    },
+    Data(Vec<u8>),
+    /// The session is over; no more data will be sent for it.
+    End,
+    /// Ingest token identifying the device, sent before any other frame.
+    Auth {
+        token: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    UnknownFrame(u8),
+    FrameTooLarge(u32),
+    InvalidHello,
+    InvalidAuth,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            Self::UnknownFrame(v) => write!(f, "unknown frame kind {v}"),
+            Self::FrameTooLarge(v) => write!(f, "frame of {v} bytes is too large"),
+            Self::InvalidHello => write!(f, "invalid hello frame"),
+            Self::InvalidAuth => write!(f, "invalid auth frame"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Detect,
+    Legacy,
+    Framed,
+}
+
+/// Incremental decoder for one connection, fed with bytes as they arrive.
+///
+/// Data that does not start with the handshake is passed through as
+/// [`Frame::Data`].
+#[derive(Debug, Default)]
+pub struct Decoder {
+    mode: Mode,
+    buf: Vec<u8>,
+}
+
+impl Decoder {
+    #[must_use]
+    pub fn is_framed(&self) -> bool {
+        self.mode == Mode::Framed
+    }
+
+    /// Feeds received bytes, returning every frame completed by them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a framed sender violates the protocol. The
+    /// connection should be dropped afterwards.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Frame>, DecodeError> {
+        if self.mode == Mode::Legacy {
+            return Ok(vec![Frame::Data(data.to_vec())]);
+        }
+
+        self.buf.extend_from_slice(data);
+        if self.mode == Mode::Detect {
+            let n = self.buf.len().min(MAGIC.len());
+            if self.buf[..n] != MAGIC[..n] {
+                self.mode = Mode::Legacy;
+                return Ok(vec![Frame::Data(std::mem::take(&mut self.buf))]);
+            }
+            if self.buf.len() <= MAGIC.len() {
+                return Ok(Vec::new());
+            }
+            let version = self.buf[MAGIC.len()];
+            if version != VERSION {
+                return Err(DecodeError::UnsupportedVersion(version));
+            }
+            self.buf.drain(..=MAGIC.len());
+            self.mode = Mode::Framed;
+        }
+
+        let mut frames = Vec::new();
+        while self.buf.len() >= HEADER_LEN {
+            let len = u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]);
+            if len > MAX_FRAME_LEN {
+                return Err(DecodeError::FrameTooLarge(len));
+            }
+            let end = HEADER_LEN + len as usize;
+            if self.buf.len() < end {
+                break;
+            }
+            let kind = self.buf[0];
+            let payload = &self.buf[HEADER_LEN..end];
+            frames.push(match kind {
+                KIND_HELLO => {
+                    let session = payload
+                        .get(..16)
+                        .and_then(|v| Uuid::from_slice(v).ok())
+                        .ok_or(DecodeError::InvalidHello)?;
+                    let device_id = std::str::from_utf8(&payload[16..])
+                        .map_err(|_| DecodeError::InvalidHello)?;
+                    Frame::Hello {
+                        session,
+                        device_id: (!device_id.is_empty()).then(|| device_id.to_owned()),
+                    }
+                }
+                KIND_DATA => Frame::Data(payload.to_vec()),
+                KIND_END => Frame::End,
+                KIND_AUTH => Frame::Auth {
+                    token: String::from_utf8(payload.to_vec())
+                        .map_err(|_| DecodeError::InvalidAuth)?,
+                },
+                v => return Err(DecodeError::UnknownFrame(v)),
+            });
+            self.buf.drain(..end);
+        }
+        Ok(frames)
+    }
+
+    /// Returns leftover bytes once the sender has closed the connection.
+    ///
+    /// A legacy sender that wrote fewer bytes than the handshake would
+    /// otherwise be lost.
+    pub fn finish(&mut self) -> Option<Frame> {
+        (self.mode == Mode::Detect && !self.buf.is_empty())
+            .then(|| Frame::Data(std::mem::take(&mut self.buf)))
+    }
+}
+
+/// Acknowledgement sent back once submitted data has been stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Receipt {
+    /// ID of the entry holding the submitted data.
+    pub id: u64,
+    /// Whether the data was merged into an existing entry.
+    pub merged: bool,
+}
+
+impl Receipt {
+    /// Serialises the receipt as a frame for framed senders.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = vec![KIND_RECEIPT];
+        v.extend_from_slice(&9_u32.to_be_bytes());
+        v.extend_from_slice(&self.id.to_be_bytes());
+        v.push(u8::from(self.merged));
+        v
+    }
+
+    /// Parses a receipt frame as written by [`Receipt::encode`].
+    #[must_use]
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        match data.split_first()? {
+            (&KIND_RECEIPT, rest) if rest.len() == 4 + 9 => Some(Self {
+                id: u64::from_be_bytes(rest[4..12].try_into().ok()?),
+                merged: match rest[12] {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Receipt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ACK {}", self.id)?;
+        if self.merged {
+            write!(f, " MERGED")?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes a framed sender writes before its first frame.
+#[must_use]
+pub fn handshake() -> Vec<u8> {
+    let mut v = MAGIC.to_vec();
+    v.push(VERSION);
+    v
+}
+
+/// Serialises a frame for sending.
+///
+/// # Panics
+///
+/// Panics if the payload is larger than [`MAX_FRAME_LEN`].
+#[must_use]
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let (kind, payload) = match frame {
+        Frame::Hello { session, device_id } => {
+            let mut v = session.as_bytes().to_vec();
+            v.extend_from_slice(device_id.as_deref().unwrap_or_default().as_bytes());
+            (KIND_HELLO, v)
+        }
+        Frame::Data(v) => (KIND_DATA, v.clone()),
+        Frame::End => (KIND_END, Vec::new()),
+        Frame::Auth { token } => (KIND_AUTH, token.as_bytes().to_vec()),
+    };
+    let len = u32::try_from(payload.len()).unwrap();
+    assert!(len <= MAX_FRAME_LEN, "frame payload too large");
+    let mut v = Vec::with_capacity(HEADER_LEN + payload.len());
+    v.push(kind);
+    v.extend_from_slice(&len.to_be_bytes());
+    v.extend(payload);
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello() -> Frame {
+        Frame::Hello {
+            session: Uuid::from_u128(0x1234),
+            device_id: Some("hackintosh".to_owned()),
+        }
+    }
+
+    fn stream() -> Vec<u8> {
+        let mut v = handshake();
+        v.extend(encode(&hello()));
+        v.extend(encode(&Frame::Data(b"boot\n".to_vec())));
+        v.extend(encode(&Frame::End));
+        v
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let expected = vec![hello(), Frame::Data(b"boot\n".to_vec()), Frame::End];
+        let data = stream();
+        for size in 1..data.len() {
+            let mut decoder = Decoder::default();
+            let mut frames = Vec::new();
+            for chunk in data.chunks(size) {
+                frames.extend(decoder.feed(chunk).unwrap());
+            }
+            assert!(decoder.is_framed());
+            assert_eq!(frames, expected, "reads of {size} bytes");
+            assert_eq!(decoder.finish(), None);
+        }
+    }
+
+    #[test]
+    fn passes_legacy_data_through() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.feed(b"WR").unwrap(),
+            Vec::<Frame>::new(),
+            "could still be the handshake"
+        );
+        assert_eq!(
+            decoder.feed(b"ITE\n").unwrap(),
+            vec![Frame::Data(b"WRITE\n".to_vec())]
+        );
+        assert!(!decoder.is_framed());
+        assert_eq!(
+            decoder.feed(b"more").unwrap(),
+            vec![Frame::Data(b"more".to_vec())]
+        );
+
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.feed(b"WRE").unwrap(), Vec::<Frame>::new());
+        assert_eq!(decoder.finish(), Some(Frame::Data(b"WRE".to_vec())));
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION + 1);
+        assert_eq!(
+            Decoder::default().feed(&data),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut data = handshake();
+        data.push(0x7F);
+        data.extend(0_u32.to_be_bytes());
+        assert_eq!(
+            Decoder::default().feed(&data),
+            Err(DecodeError::UnknownFrame(0x7F))
+        );
+
+        let mut data = handshake();
+        data.push(KIND_HELLO);
+        data.extend(3_u32.to_be_bytes());
+        data.extend(b"abc");
+        assert_eq!(
+            Decoder::default().feed(&data),
+            Err(DecodeError::InvalidHello)
+        );
+    }
+
+    #[test]
+    fn rejects_oversized_frames_before_buffering_them() {
+        let mut data = handshake();
+        data.push(KIND_DATA);
+        data.extend((MAX_FRAME_LEN + 1).to_be_bytes());
+        assert_eq!(
+            Decoder::default().feed(&data),
+            Err(DecodeError::FrameTooLarge(MAX_FRAME_LEN + 1))
+        );
+    }
+
+    #[test]
+    fn round_trips_receipts() {
+        for receipt in [
+            Receipt {
+                id: 1,
+                merged: false,
+            },
+            Receipt {
+                id: 1,
+                merged: true,
+            },
+        ] {
+            assert_eq!(Receipt::decode(&receipt.encode()), Some(receipt));
+        }
+        assert_eq!(Receipt::decode(&[KIND_RECEIPT, 0, 0]), None);
+        let mut bad_flag = Receipt {
+            id: 1,
+            merged: true,
+        }
+        .encode();
+        bad_flag[13] = 2;
+        assert_eq!(Receipt::decode(&bad_flag), None);
+    }
+}
@@ -0,0 +1,141 @@
+//! Evicts entries past the retention limits, oldest first.
+//!
+//! Unsaved entries are evicted once they haven't been updated for too long,
+//! and by the ingest task when they take up more memory together than the
+//! ingest limits allow. Saved entries are only evicted if quotas are set for
+//! them.
+
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+
+/// Evictions kept for the admin API.
+const RECENT_LEN: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// Seconds after its last update an unsaved entry is evicted.
+    pub max_unsaved_age_secs: Option<u64>,
+    /// Saved entries kept before the oldest are evicted.
+    pub max_saved_count: Option<usize>,
+    /// Bytes of saved entries kept before the oldest are evicted.
+    pub max_saved_bytes: Option<u64>,
+    /// Seconds between checks.
+    pub interval_secs: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            max_unsaved_age_secs: None,
+            max_saved_count: None,
+            max_saved_bytes: None,
+            interval_secs: 60,
+        }
+    }
+}
+
+impl Retention {
+    /// When an unsaved entry last updated at `last_updated` will be evicted
+    /// for its age, if ever.
+    pub fn expires(&self, last_updated: u64) -> Option<u64> {
+        self.max_unsaved_age_secs
+            .map(|v| last_updated.saturating_add(v.saturating_mul(1_000_000)))
+    }
+}
+
+/// Why an entry was evicted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reason {
+    UnsavedAge,
+    UnsavedBytes,
+    SavedCount,
+    SavedBytes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Eviction {
+    pub id: u64,
+    pub time: u64,
+    pub reason: Reason,
+    /// Bytes of data the entry held.
+    pub size: u64,
+}
+
+/// The most recent evictions.
+#[derive(Debug, Default)]
+pub struct Evictions {
+    recent: Mutex<VecDeque<Eviction>>,
+}
+
+impl Evictions {
+    pub fn record(&self, eviction: Eviction) {
+        println!(
+            "Evicted {} ({:?}, {} bytes)",
+            eviction.id, eviction.reason, eviction.size
+        );
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_LEN {
+            recent.pop_front();
+        }
+        recent.push_back(eviction);
+    }
+
+    pub fn recent(&self) -> Vec<Eviction> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Evicts saved entries past the quotas, oldest first.
+async fn evict_saved(state: &crate::state::AppState, now: u64) -> std::io::Result<()> {
+    let retention = &state.config.retention;
+    if retention.max_saved_count.is_none() && retention.max_saved_bytes.is_none() {
+        return Ok(());
+    }
+    let mut listed = state.logs.saved().await?;
+    listed.sort_unstable_by_key(|v| (v.partial.last_updated, v.partial.id));
+    let mut count = listed.len();
+    let mut bytes: u64 = listed.iter().map(|v| v.size).sum();
+    for v in listed {
+        let reason = if retention.max_saved_count.is_some_and(|m| count > m) {
+            Reason::SavedCount
+        } else if retention.max_saved_bytes.is_some_and(|m| bytes > m) {
+            Reason::SavedBytes
+        } else {
+            break;
+        };
+        let id = v.partial.id;
+        state.logs.delete(id).await?;
+        state.evictions.record(Eviction {
+            id,
+            time: now,
+            reason,
+            size: v.size,
+        });
+        count -= 1;
+        bytes -= v.size;
+    }
+    Ok(())
+}
+
+/// Starts evicting entries periodically.
+pub fn start(state: web::Data<crate::state::AppState>) {
+    tokio::spawn(async move {
+        let secs = state.config.retention.interval_secs.max(1);
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let now = crate::ingest::now_micros();
+            let retention = &state.config.retention;
+            let max_age = retention
+                .max_unsaved_age_secs
+                .map(|v| v.saturating_mul(1_000_000));
+            state.ingest.evict(max_age, now).await;
+            if let Err(e) = evict_saved(&state, now).await {
+                println!("Failed to evict saved logs: {e}");
+            }
+        }
+    });
+}
@@ -1,21 +1,109 @@
-use actix_web::{delete, get, post, web, HttpResponse, Responder};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{ErrorKind, Read},
+};
+
+use actix_web::{
+    delete, dev::PeerAddr, get, http::header, post, web, HttpRequest, HttpResponse, Responder,
+};
+use serde::Deserialize;
+use wred_server::line::{Level, LogLine};
+
+#[derive(Debug, Deserialize)]
+pub struct IngestQuery {
+    submitter: Option<String>,
+    description: Option<String>,
+    device: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    label: Option<String>,
+    /// Minimum severity of any label, as a tag such as `err`.
+    severity: Option<String>,
+    /// Text the data must contain.
+    q: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinesQuery {
+    module: Option<String>,
+    function: Option<String>,
+    /// Minimum level, as a tag such as `warn`.
+    level: Option<String>,
+}
+
+/// Decompresses gzip uploads, which are recognised by their magic bytes.
+///
+/// Output beyond `limit` bytes is replaced with a truncation marker.
+fn decompress(body: &[u8], limit: usize) -> std::io::Result<Cow<'_, [u8]>> {
+    if body.starts_with(&[0x1F, 0x8B]) {
+        let mut v = Vec::new();
+        flate2::read::MultiGzDecoder::new(body)
+            .take(limit as u64 + 1)
+            .read_to_end(&mut v)?;
+        if v.len() > limit {
+            v.truncate(limit);
+            v.extend(crate::ingest::truncation_marker("upload too large").bytes());
+        }
+        Ok(Cow::Owned(v))
+    } else {
+        Ok(Cow::Borrowed(body))
+    }
+}
+
+/// The token of an `Authorization: Bearer` header, kept out of the query
+/// string so it doesn't end up in access logs.
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(str::trim)
+}
 
 #[get("/all")]
-async fn get_logs(data: web::Data<super::state::AppState>) -> impl Responder {
-    let data = data.into_inner();
-    let logs = data.logs.lock().await;
-    println!("{logs:?}");
-    let resp: Vec<_> = logs
-        .iter()
-        .map(|(&id, v)| wred_server::LogEntryPartial {
-            id,
-            addr: v.addr,
-            last_updated: v.last_updated,
-            is_saved: data.config.log_dir.join(format!("{}.log", id)).exists(),
+async fn get_logs(
+    query: web::Query<ListQuery>,
+    data: web::Data<super::state::AppState>,
+) -> impl Responder {
+    let severity = match query.severity.as_deref().map(Level::from_tag) {
+        Some(None) => return HttpResponse::BadRequest().body("Unknown severity"),
+        Some(v) => v,
+        None => None,
+    };
+    let needle = query.q.as_deref().unwrap_or_default().as_bytes();
+    let listed = match data.logs.list(needle).await {
+        Ok(v) => v,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Failed to list logs: {e}"))
+        }
+    };
+    let now = crate::ingest::now_micros();
+    let mut resp: Vec<_> = listed
+        .into_iter()
+        .map(|v| wred_server::LogEntryPartial {
+            outcome: data.classifier.outcome(
+                v.partial.outcome,
+                v.is_ended,
+                v.partial.last_updated,
+                now,
+            ),
+            expires: data
+                .config
+                .retention
+                .expires(v.partial.last_updated)
+                .filter(|_| !v.partial.is_saved),
+            ..v.partial
         })
         .collect();
+    resp.retain(|v| {
+        query
+            .label
+            .as_ref()
+            .is_none_or(|l| v.labels.contains_key(l))
+            && severity.is_none_or(|s| v.labels.values().any(|&v| v >= s))
+    });
     postcard::to_allocvec(&resp).map_or_else(
-        |e| HttpResponse::InternalServerError().body(format!("Failed to serialise: {}", e)),
+        |e| HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
         |v| HttpResponse::Ok().body(v),
     )
 }
@@ -26,12 +114,120 @@ async fn get_log(
     data: web::Data<super::state::AppState>,
 ) -> impl Responder {
     let id: u64 = path.into_inner().parse().unwrap();
-    let data = data.into_inner();
-    let logs = data.logs.lock().await;
-    logs.get(&id).map_or_else(
-        || HttpResponse::NotFound().finish(),
-        |v| HttpResponse::Ok().body(postcard::to_allocvec(&v).unwrap()),
-    )
+    match data.logs.with_entry(id, postcard::to_allocvec).await {
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to load log: {e}")),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Ok(Some(v)) => HttpResponse::Ok().body(v.unwrap()),
+    }
+}
+
+/// The entry's data exactly as received.
+#[get("/{id:[[:digit:]]+}/raw")]
+async fn get_log_raw(
+    path: web::Path<String>,
+    data: web::Data<super::state::AppState>,
+) -> impl Responder {
+    let id: u64 = path.into_inner().parse().unwrap();
+    match data.logs.with_entry(id, |v| v.data.clone()).await {
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to load log: {e}")),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Ok(Some(v)) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .insert_header((
+                "Content-Disposition",
+                format!("attachment; filename=\"{id}.log\""),
+            ))
+            .body(v),
+    }
+}
+
+/// The entry's parsed lines, optionally filtered.
+#[get("/{id:[[:digit:]]+}/lines")]
+async fn get_log_lines(
+    path: web::Path<String>,
+    query: web::Query<LinesQuery>,
+    data: web::Data<super::state::AppState>,
+) -> impl Responder {
+    let id: u64 = path.into_inner().parse().unwrap();
+    let level = match query.level.as_deref().map(Level::from_tag) {
+        Some(None) => return HttpResponse::BadRequest().body("Unknown level"),
+        Some(v) => v,
+        None => None,
+    };
+    let ent = match data.logs.with_entry(id, |v| v.data.clone()).await {
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Failed to load log: {e}"))
+        }
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Ok(Some(v)) => v,
+    };
+    // Parsed on a blocking thread, as it's slow for long entries.
+    let query = query.into_inner();
+    let res = web::block(move || {
+        let mut lines = LogLine::parse_all(&ent);
+        lines.retain(|v| {
+            level.is_none_or(|l| v.level >= l)
+                && (query.module.is_none() || v.module == query.module)
+                && (query.function.is_none() || v.function == query.function)
+        });
+        postcard::to_allocvec(&lines)
+    })
+    .await;
+    match res {
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to parse: {e}")),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
+        Ok(Ok(v)) => HttpResponse::Ok().body(v),
+    }
+}
+
+/// The templates of the entry's lines, rarest across all entries first.
+#[get("/{id:[[:digit:]]+}/templates")]
+async fn get_log_templates(
+    path: web::Path<String>,
+    data: web::Data<super::state::AppState>,
+) -> impl Responder {
+    let id: u64 = path.into_inner().parse().unwrap();
+    let ent = match data.logs.with_entry(id, |v| v.data.clone()).await {
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Failed to load log: {e}"))
+        }
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Ok(Some(v)) => v,
+    };
+    // Matched on a blocking thread, as it's slow for long entries.
+    let templates = std::sync::Arc::clone(&data.templates);
+    match web::block(move || postcard::to_allocvec(&templates.stats(&ent))).await {
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to match: {e}")),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
+        Ok(Ok(v)) => HttpResponse::Ok().body(v),
+    }
+}
+
+/// The lines of two entries with those that differ, ignoring kernel
+/// timestamps, addresses and pointers.
+#[get("/diff/{a:[[:digit:]]+}/{b:[[:digit:]]+}")]
+async fn get_diff(
+    path: web::Path<(String, String)>,
+    data: web::Data<super::state::AppState>,
+) -> impl Responder {
+    let (a, b) = path.into_inner();
+    let (a, b): (u64, u64) = (a.parse().unwrap(), b.parse().unwrap());
+    // Diffed without the lock on a blocking thread, so neither ingest nor
+    // other requests are held up.
+    let a = data.logs.with_entry(a, |v| v.data.clone()).await;
+    let b = data.logs.with_entry(b, |v| v.data.clone()).await;
+    let (a, b) = match (a, b) {
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::InternalServerError().body(format!("Failed to load log: {e}"))
+        }
+        (Ok(Some(a)), Ok(Some(b))) => (a, b),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    match web::block(move || postcard::to_allocvec(&wred_server::diff::Diff::new(&a, &b))).await {
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to diff: {e}")),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
+        Ok(Ok(v)) => HttpResponse::Ok().body(v),
+    }
 }
 
 #[delete("/{id:[[:digit:]]+}")]
@@ -42,30 +238,31 @@ async fn delete_log(
 ) -> impl Responder {
     let id: u64 = path.into_inner().parse().unwrap();
     let data = data.into_inner();
-    let mut logs = data.logs.lock().await;
-    postcard::from_bytes(&body).map_or_else(
-        move |v| HttpResponse::BadRequest().body(format!("Failed to deserialise: {}", v)),
-        |v: String| {
-            if v == data.config.secret {
-                logs.remove(&id).map_or_else(
-                    || HttpResponse::NotFound().finish(),
-                    |v| {
-                        let _e =
-                            std::fs::remove_file(data.config.log_dir.join(format!("{}.log", id)));
-                        postcard::to_allocvec(&v).map_or_else(
-                            |e| {
-                                HttpResponse::InternalServerError()
-                                    .body(format!("Failed to serialise: {}", e))
-                            },
-                            |v| HttpResponse::Ok().body(v),
-                        )
-                    },
-                )
-            } else {
-                HttpResponse::Unauthorized().finish()
-            }
-        },
-    )
+    match postcard::from_bytes::<String>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok(v) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok(_) => {
+            let removed = match data.logs.delete(id).await {
+                Ok(v) => v,
+                Err(e) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to delete log: {e}"))
+                }
+            };
+            removed.map_or_else(
+                || HttpResponse::NotFound().finish(),
+                |v| {
+                    postcard::to_allocvec(&v).map_or_else(
+                        |e| {
+                            HttpResponse::InternalServerError()
+                                .body(format!("Failed to serialise: {e}"))
+                        },
+                        |v| HttpResponse::Ok().body(v),
+                    )
+                },
+            )
+        }
+    }
 }
 
 #[post("/{id:[[:digit:]]+}")]
@@ -76,35 +273,168 @@ async fn save_log(
 ) -> impl Responder {
     let id: u64 = path.into_inner().parse().unwrap();
     let data = data.into_inner();
-    let logs = data.logs.lock().await;
-    postcard::from_bytes(&body).map_or_else(
-        move |v| HttpResponse::BadRequest().body(format!("Failed to deserialise: {}", v)),
-        |v: String| {
-            if v == data.config.secret {
-                logs.get(&id).map_or_else(
-                    || HttpResponse::NotFound().finish(),
-                    |v| {
-                        postcard::to_allocvec(&(id, v)).map_or_else(
-                            |e| {
-                                HttpResponse::InternalServerError()
-                                    .body(format!("Failed to serialise: {}", e))
-                            },
-                            |v| {
-                                std::fs::write(data.config.log_dir.join(format!("{id}.log")), &v)
-                                    .map_or_else(
-                                        |e| {
-                                            HttpResponse::InternalServerError()
-                                                .body(format!("Failed to save log: {}", e))
-                                        },
-                                        |_| HttpResponse::Ok().finish(),
-                                    )
-                            },
-                        )
-                    },
-                )
-            } else {
-                HttpResponse::Unauthorized().finish()
+    match postcard::from_bytes::<String>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok(v) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok(_) => match data.logs.save(id).await {
+            Err(e) => HttpResponse::InternalServerError().body(format!("Failed to save log: {e}")),
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Ok(true) => HttpResponse::Ok().finish(),
+        },
+    }
+}
+
+#[post("/ingest")]
+async fn ingest(
+    req: HttpRequest,
+    PeerAddr(addr): PeerAddr,
+    query: web::Query<IngestQuery>,
+    data: web::Data<super::state::AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    let device = match data.tokens.authenticate(bearer_token(&req)) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::Unauthorized().body(e.to_string()),
+    };
+    let body = match decompress(&body, data.config.limits.max_connection_bytes) {
+        Ok(v) if !v.is_empty() => v,
+        Ok(_) => return HttpResponse::BadRequest().body("Empty log"),
+        Err(e) => return HttpResponse::BadRequest().body(format!("Failed to decompress: {e}")),
+    };
+    let query = query.into_inner();
+    let metadata: BTreeMap<_, _> = [
+        ("submitter", query.submitter),
+        ("description", query.description),
+    ]
+    .into_iter()
+    .filter_map(|(k, v)| Some((k.to_owned(), v?)))
+    .collect();
+    data.ingest
+        .upload(addr, query.device, device, metadata, &body)
+        .await
+        .map_or_else(
+            || HttpResponse::ServiceUnavailable().finish(),
+            |v| HttpResponse::Ok().body(v.to_string()),
+        )
+}
+
+/// Creates an ingest token for a device, taking `(secret, name)`.
+#[post("/admin/tokens")]
+async fn create_token(data: web::Data<super::state::AppState>, body: web::Bytes) -> impl Responder {
+    match postcard::from_bytes::<(String, String)>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok((v, _)) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok((_, name)) => match data.tokens.create(name) {
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Failed to save tokens: {e}"))
             }
+            Ok(token) => postcard::to_allocvec(&token).map_or_else(
+                |e| HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
+                |v| HttpResponse::Ok().body(v),
+            ),
         },
-    )
+    }
+}
+
+/// Revokes a device's ingest token, taking `(secret, name)`.
+///
+/// Only tokens created through the API can be revoked, configured ones have to
+/// be removed from the config.
+#[delete("/admin/tokens")]
+async fn revoke_token(data: web::Data<super::state::AppState>, body: web::Bytes) -> impl Responder {
+    match postcard::from_bytes::<(String, String)>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok((v, _)) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok((_, name)) => match data.tokens.revoke(&name) {
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Failed to save tokens: {e}"))
+            }
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Ok(true) => HttpResponse::Ok().finish(),
+        },
+    }
+}
+
+/// Uploads a kext binary or dSYM to symbolicate panics with, taking
+/// `(secret, name, version, binary)`.
+#[post("/admin/symbols")]
+async fn upload_symbols(
+    data: web::Data<super::state::AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    match postcard::from_bytes::<(String, String, String, Vec<u8>)>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok((v, ..)) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok((_, name, version, binary)) => match data.symbols.add(name, version, &binary) {
+            Err(e) if matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::InvalidData) => {
+                HttpResponse::BadRequest().body(e.to_string())
+            }
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Failed to save symbols: {e}"))
+            }
+            Ok(()) => {
+                data.logs.symbolicate().await;
+                HttpResponse::Ok().finish()
+            }
+        },
+    }
+}
+
+/// Removes a kext version's symbols, taking `(secret, name, version)`.
+#[delete("/admin/symbols")]
+async fn delete_symbols(
+    data: web::Data<super::state::AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    match postcard::from_bytes::<(String, String, String)>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok((v, ..)) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok((_, name, version)) => match data.symbols.remove(&name, &version) {
+            Err(e) => {
+                HttpResponse::InternalServerError().body(format!("Failed to remove symbols: {e}"))
+            }
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Ok(true) => {
+                data.logs.symbolicate().await;
+                HttpResponse::Ok().finish()
+            }
+        },
+    }
+}
+
+/// Reloads the label rules and relabels all entries, taking the secret.
+///
+/// Returns the number of rules.
+#[post("/admin/rules/reload")]
+async fn reload_rules(data: web::Data<super::state::AppState>, body: web::Bytes) -> impl Responder {
+    match postcard::from_bytes::<String>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok(v) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok(_) => match data.labeler.reload() {
+            Err(e) => HttpResponse::BadRequest().body(format!("Invalid rules: {e}")),
+            Ok(n) => data.logs.relabel(&data.labeler).await.map_or_else(
+                |e| {
+                    HttpResponse::InternalServerError()
+                        .body(format!("Failed to relabel saved logs: {e}"))
+                },
+                |()| HttpResponse::Ok().body(n.to_string()),
+            ),
+        },
+    }
+}
+
+/// Lists the most recent evictions, oldest first, taking the secret.
+#[post("/admin/evictions")]
+async fn get_evictions(
+    data: web::Data<super::state::AppState>,
+    body: web::Bytes,
+) -> impl Responder {
+    match postcard::from_bytes::<String>(&body) {
+        Err(e) => HttpResponse::BadRequest().body(format!("Failed to deserialise: {e}")),
+        Ok(v) if v != data.config.secret => HttpResponse::Unauthorized().finish(),
+        Ok(_) => postcard::to_allocvec(&data.evictions.recent()).map_or_else(
+            |e| HttpResponse::InternalServerError().body(format!("Failed to serialise: {e}")),
+            |v| HttpResponse::Ok().body(v),
+        ),
+    }
 }
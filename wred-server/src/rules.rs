@@ -0,0 +1,88 @@
+//! Labels entries matching known failure signatures.
+//!
+//! The rules are kept in `rules.ron` next to the config and can be reloaded
+//! through the admin API, which relabels every entry.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use wred_server::line::Level;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelRule {
+    /// Regex matched against the log text.
+    pub pattern: String,
+    pub label: String,
+    pub severity: Level,
+}
+
+#[derive(Debug)]
+pub struct Labeler {
+    path: PathBuf,
+    rules: RwLock<Vec<(LabelRule, Regex)>>,
+}
+
+impl Labeler {
+    fn read(path: &Path) -> Result<Vec<(LabelRule, Regex)>, String> {
+        let rules: Vec<LabelRule> = match std::fs::File::open(path) {
+            Ok(f) => ron::de::from_reader(f).map_err(|e| e.to_string())?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.to_string()),
+        };
+        rules
+            .into_iter()
+            .map(|v| {
+                let re = Regex::new(&v.pattern).map_err(|e| format!("{}: {e}", v.label))?;
+                Ok((v, re))
+            })
+            .collect()
+    }
+
+    pub fn load() -> Self {
+        let path = PathBuf::from("./rules.ron");
+        let rules = Self::read(&path).expect("Failed reading label rules");
+        Self {
+            path,
+            rules: RwLock::new(rules),
+        }
+    }
+
+    /// Reads the rules again, keeping the old ones if they're invalid.
+    ///
+    /// Returns the number of rules.
+    pub fn reload(&self) -> Result<usize, String> {
+        let rules = Self::read(&self.path)?;
+        let len = rules.len();
+        *self.rules.write().unwrap() = rules;
+        Ok(len)
+    }
+
+    /// Adds the labels of rules matching the complete lines of `ent.data`,
+    /// starting with the line `from` is in.
+    pub fn apply(&self, ent: &mut wred_server::LogEntry, from: usize) {
+        let rules = self.rules.read().unwrap();
+        if rules.iter().all(|(v, _)| ent.labels.contains_key(&v.label)) {
+            return;
+        }
+        let lines = wred_server::line::complete_lines(&ent.data, from);
+        if lines.is_empty() {
+            return;
+        }
+        let text = String::from_utf8_lossy(&ent.data[lines]);
+        for (rule, re) in rules.iter() {
+            if !ent.labels.contains_key(&rule.label) && re.is_match(&text) {
+                ent.labels.insert(rule.label.clone(), rule.severity);
+            }
+        }
+    }
+
+    /// Labels `ent` from scratch, e.g. after the rules were reloaded.
+    pub fn relabel(&self, ent: &mut wred_server::LogEntry) {
+        ent.labels.clear();
+        self.apply(ent, 0);
+    }
+}
@@ -1,19 +1,125 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use wred_server::store::{LogStore, MemoryStore};
+
+use crate::{
+    access::{AccessList, Rejections},
+    classify::{Classifier, OutcomeRules},
+    extract::MetadataRule,
+    retention::{Evictions, Retention},
+    tls::TlsConfig,
+    tokens::{DeviceToken, Tokens},
+};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub ip: String,
     pub api_port: u16,
     pub logger_port: u16,
+    /// Port for raw log datagrams, if any.
+    #[serde(default)]
+    pub udp_port: Option<u16>,
+    /// Port for RFC 5424/3164 syslog datagrams, if any.
+    #[serde(default)]
+    pub syslog_port: Option<u16>,
     pub secret: String,
+    /// Where saved logs are kept.
     pub log_dir: PathBuf,
+    #[serde(default)]
+    pub store: StoreKind,
+    /// How long entries are kept.
+    #[serde(default)]
+    pub retention: Retention,
+    #[serde(default)]
+    pub limits: IngestLimits,
+    /// Who may send logs to the logger ports.
+    #[serde(default)]
+    pub logger_access: AccessList,
+    /// Who may use the API and web client.
+    #[serde(default)]
+    pub api_access: AccessList,
+    /// Ingest tokens, in addition to those created through the admin API.
+    #[serde(default)]
+    pub devices: Vec<DeviceToken>,
+    /// Refuse senders without a known token.
+    #[serde(default)]
+    pub strict_tokens: bool,
+    /// Rules filling entry metadata from the log text.
+    #[serde(default = "crate::extract::default_rules")]
+    pub metadata_rules: Vec<MetadataRule>,
+    /// Markers and timeout telling how each boot ended.
+    #[serde(default)]
+    pub outcome_rules: OutcomeRules,
+    /// Serve the API, and if enabled the logger port, over TLS.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// How saved logs are kept.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum StoreKind {
+    /// An SQLite database, `logs.sqlite3` in `log_dir`.
+    #[default]
+    Database,
+    /// A data file and an entry file per log in `log_dir`.
+    Files,
+    /// Memory only, so saved logs are lost on restart.
+    Memory,
+}
+
+impl StoreKind {
+    pub fn open(self, log_dir: &Path) -> std::io::Result<Arc<dyn LogStore>> {
+        Ok(match self {
+            Self::Database => Arc::new(crate::db::Database::open(&log_dir.join("logs.sqlite3"))?),
+            Self::Files => Arc::new(crate::files::FileStore::new(log_dir.to_owned())),
+            Self::Memory => Arc::new(MemoryStore::default()),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IngestLimits {
+    /// Bytes stored per connection or upload before the rest is dropped.
+    pub max_connection_bytes: usize,
+    /// Seconds without data before a connection is closed.
+    pub idle_timeout_secs: u64,
+    /// Seconds before any connection is closed.
+    pub total_timeout_secs: u64,
+    pub max_connections_per_ip: usize,
+    /// Bytes held in unsaved entries before the oldest are evicted to make
+    /// room, or if that isn't enough, further data is dropped.
+    pub max_unsaved_bytes: usize,
+}
+
+impl Default for IngestLimits {
+    fn default() -> Self {
+        Self {
+            max_connection_bytes: 64 * 1024 * 1024,
+            idle_timeout_secs: 30 * 60,
+            total_timeout_secs: 24 * 60 * 60,
+            max_connections_per_ip: 8,
+            max_unsaved_bytes: 1024 * 1024 * 1024,
+        }
+    }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct AppState {
     pub config: ServerConfig,
-    pub logs: Arc<Mutex<HashMap<u64, wred_server::LogEntry>>>,
+    /// All entries, held in memory or saved.
+    pub logs: crate::logs::Logs,
+    pub ingest: crate::ingest::Ingest,
+    pub rejections: Rejections,
+    pub tokens: Tokens,
+    pub symbols: Arc<crate::symbols::Symbols>,
+    pub classifier: Classifier,
+    pub labeler: Arc<crate::rules::Labeler>,
+    pub templates: Arc<crate::clusters::Templates>,
+    pub evictions: Arc<Evictions>,
+    pub tls: Option<Arc<rustls::ServerConfig>>,
 }
@@ -0,0 +1,179 @@
+//! Storage for saved entries.
+//!
+//! Entries being received are kept in memory by the server, and once saved
+//! also in a [`LogStore`], whose backend is chosen in the server config.
+
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    sync::RwLock,
+};
+
+use crate::{LogEntry, LogEntryPartial};
+
+/// A stored entry as listed, with whether it ended, to tell hangs apart with.
+#[derive(Debug, Clone)]
+pub struct Listed {
+    pub partial: LogEntryPartial,
+    pub is_ended: bool,
+    /// Bytes of data stored.
+    pub size: u64,
+}
+
+impl Listed {
+    #[must_use]
+    pub fn new(id: u64, ent: &LogEntry) -> Self {
+        Self {
+            partial: LogEntryPartial {
+                is_saved: true,
+                ..ent.partial(id)
+            },
+            is_ended: ent.is_ended,
+            size: ent.data.len() as u64,
+        }
+    }
+}
+
+/// The error for appending at `from` to an entry with only `len` bytes of
+/// data stored, which would leave a gap.
+#[must_use]
+pub fn missing_data(id: u64, len: usize, from: usize) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{id} has {len} bytes stored, can't append at {from}"),
+    )
+}
+
+pub trait LogStore: std::fmt::Debug + Send + Sync {
+    /// Lists the stored entries, without loading their data where possible.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entries can't be read.
+    fn list(&self) -> Result<Vec<Listed>>;
+
+    /// Loads the entry `id`, if stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry can't be read or decoded.
+    fn get(&self, id: u64) -> Result<Option<LogEntry>>;
+
+    /// Whether the entry `id` is stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the store can't be read.
+    fn contains(&self, id: u64) -> Result<bool> {
+        Ok(self.get(id)?.is_some())
+    }
+
+    /// Appends `data` to the stored data of the entry `id` at `from`,
+    /// returning whether it's stored. Entries that aren't are left alone.
+    ///
+    /// Data stored past `from` is replaced, so appending the same data again
+    /// is harmless.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the data can't be written, or if less than `from`
+    /// bytes are stored, which would leave a gap.
+    fn append(&self, id: u64, from: usize, data: &[u8]) -> Result<bool>;
+
+    /// Replaces everything stored of the entry `id` but its data with `ent`,
+    /// returning whether it's stored. Entries that aren't are left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry can't be written.
+    fn update(&self, id: u64, ent: &LogEntry) -> Result<bool>;
+
+    /// Stores `ent`, replacing what was stored of it before.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry can't be written.
+    fn save(&self, id: u64, ent: &LogEntry) -> Result<()>;
+
+    /// Deletes a stored entry, returning whether there was one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry can't be removed.
+    fn delete(&self, id: u64) -> Result<bool>;
+
+    /// IDs of the stored entries whose data contains `needle`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entries can't be read.
+    fn search(&self, needle: &[u8]) -> Result<Vec<u64>>;
+}
+
+/// Keeps entries in memory only, so they are lost on restart.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: RwLock<HashMap<u64, LogEntry>>,
+}
+
+impl LogStore for MemoryStore {
+    fn list(&self) -> Result<Vec<Listed>> {
+        let entries = self.entries.read().unwrap();
+        Ok(entries.iter().map(|(&id, v)| Listed::new(id, v)).collect())
+    }
+
+    fn get(&self, id: u64) -> Result<Option<LogEntry>> {
+        let entries = self.entries.read().unwrap();
+        Ok(entries.get(&id).cloned())
+    }
+
+    fn contains(&self, id: u64) -> Result<bool> {
+        let entries = self.entries.read().unwrap();
+        Ok(entries.contains_key(&id))
+    }
+
+    fn append(&self, id: u64, from: usize, data: &[u8]) -> Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        let Some(ent) = entries.get_mut(&id) else {
+            return Ok(false);
+        };
+        if ent.data.len() < from {
+            return Err(missing_data(id, ent.data.len(), from));
+        }
+        ent.data.truncate(from);
+        ent.data.extend_from_slice(data);
+        Ok(true)
+    }
+
+    fn update(&self, id: u64, ent: &LogEntry) -> Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        let Some(v) = entries.get_mut(&id) else {
+            return Ok(false);
+        };
+        *v = LogEntry {
+            data: std::mem::take(&mut v.data),
+            ..ent.without_data()
+        };
+        Ok(true)
+    }
+
+    fn save(&self, id: u64, ent: &LogEntry) -> Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(id, ent.clone());
+        Ok(())
+    }
+
+    fn delete(&self, id: u64) -> Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        Ok(entries.remove(&id).is_some())
+    }
+
+    fn search(&self, needle: &[u8]) -> Result<Vec<u64>> {
+        let entries = self.entries.read().unwrap();
+        Ok(entries
+            .iter()
+            .filter(|(_, v)| v.contains(needle))
+            .map(|(&id, _)| id)
+            .collect())
+    }
+}
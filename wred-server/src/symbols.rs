@@ -0,0 +1,391 @@
+//! Symbolication of kext backtraces from uploaded Mach-O binaries or dSYMs.
+//!
+//! Only the symbol table is read, so a stripped release binary gives exported
+//! symbols only while its dSYM gives all of them.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use uuid::Uuid;
+use wred_server::panic::{Kext, PanicReport};
+
+const FAT_MAGIC: u32 = 0xCAFE_BABE;
+const MH_MAGIC: u32 = 0xFEED_FACE;
+const MH_MAGIC_64: u32 = 0xFEED_FACF;
+const CPU_TYPE_X86_64: u32 = 0x0100_0007;
+const LC_SYMTAB: u32 = 0x2;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_UUID: u32 = 0x1B;
+/// `nlist_64` bits marking debugger entries, and the type of the symbol.
+const N_STAB: u8 = 0xE0;
+const N_TYPE: u8 = 0x0E;
+const N_SECT: u8 = 0x0E;
+const TRUNCATED: &str = "truncated Mach-O file";
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Result<u32, &'static str> {
+    let v = data
+        .get(offset..offset.saturating_add(4))
+        .and_then(|v| <[u8; 4]>::try_from(v).ok())
+        .ok_or(TRUNCATED)?;
+    Ok(if big_endian {
+        u32::from_be_bytes(v)
+    } else {
+        u32::from_le_bytes(v)
+    })
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, &'static str> {
+    data.get(offset..offset.saturating_add(8))
+        .and_then(|v| <[u8; 8]>::try_from(v).ok())
+        .map(u64::from_le_bytes)
+        .ok_or(TRUNCATED)
+}
+
+/// Returns the x86_64 slice of a universal binary, or `data` if it's thin.
+fn thin(data: &[u8]) -> Result<&[u8], &'static str> {
+    if read_u32(data, 0, true) != Ok(FAT_MAGIC) {
+        return Ok(data);
+    }
+    for i in 0..read_u32(data, 4, true)? as usize {
+        let arch = 8 + i * 20;
+        if read_u32(data, arch, true)? == CPU_TYPE_X86_64 {
+            let offset = read_u32(data, arch + 8, true)? as usize;
+            let size = read_u32(data, arch + 12, true)? as usize;
+            return data.get(offset..offset + size).ok_or(TRUNCATED);
+        }
+    }
+    Err("no x86_64 slice in universal binary")
+}
+
+/// Defined symbols of a kext, by address.
+#[derive(Debug)]
+pub struct SymbolTable {
+    uuid: Option<Uuid>,
+    /// Address of `__TEXT`, which the kext's load address corresponds to.
+    text_vmaddr: u64,
+    symbols: Vec<(u64, String)>,
+}
+
+impl SymbolTable {
+    pub fn parse(data: &[u8]) -> Result<Self, &'static str> {
+        let data = thin(data)?;
+        match read_u32(data, 0, false)? {
+            MH_MAGIC_64 => {}
+            MH_MAGIC => return Err("32-bit Mach-O files aren't supported"),
+            _ => return Err("not a Mach-O file"),
+        }
+        if read_u32(data, 4, false)? != CPU_TYPE_X86_64 {
+            return Err("not an x86_64 Mach-O file");
+        }
+
+        let mut uuid = None;
+        let mut text_vmaddr = 0;
+        let mut symtab = None;
+        let mut cmd = 32;
+        for _ in 0..read_u32(data, 16, false)? {
+            let size = read_u32(data, cmd + 4, false)? as usize;
+            if size < 8 {
+                return Err("invalid load command");
+            }
+            match read_u32(data, cmd, false)? {
+                LC_SEGMENT_64
+                    if data.get(cmd + 8..cmd + 24) == Some(&b"__TEXT\0\0\0\0\0\0\0\0\0\0"[..]) =>
+                {
+                    text_vmaddr = read_u64(data, cmd + 24)?;
+                }
+                LC_UUID => {
+                    uuid = data
+                        .get(cmd + 8..cmd + 24)
+                        .and_then(|v| Uuid::from_slice(v).ok());
+                }
+                LC_SYMTAB => {
+                    symtab = Some((
+                        read_u32(data, cmd + 8, false)? as usize,
+                        read_u32(data, cmd + 12, false)? as usize,
+                        read_u32(data, cmd + 16, false)? as usize,
+                        read_u32(data, cmd + 20, false)? as usize,
+                    ));
+                }
+                _ => {}
+            }
+            cmd += size;
+        }
+
+        let (symoff, nsyms, stroff, strsize) = symtab.ok_or("no symbol table")?;
+        let strings = data.get(stroff..stroff + strsize).ok_or(TRUNCATED)?;
+        let mut symbols = Vec::new();
+        for i in 0..nsyms {
+            let sym = symoff + i * 16;
+            let kind = *data.get(sym + 4).ok_or(TRUNCATED)?;
+            if kind & N_STAB != 0 || kind & N_TYPE != N_SECT {
+                continue;
+            }
+            let name = strings
+                .get(read_u32(data, sym, false)? as usize..)
+                .and_then(|v| v.split(|&b| b == 0).next())
+                .filter(|v| !v.is_empty());
+            if let Some(name) = name {
+                symbols.push((
+                    read_u64(data, sym + 8)?,
+                    String::from_utf8_lossy(name).into_owned(),
+                ));
+            }
+        }
+        if symbols.is_empty() {
+            return Err("no defined symbols");
+        }
+        symbols.sort_by_key(|(v, _)| *v);
+        symbols.dedup_by_key(|(v, _)| *v);
+        Ok(Self {
+            uuid,
+            text_vmaddr,
+            symbols,
+        })
+    }
+
+    /// Formats `address` as `symbol + 0xoffset`, given where the kext was
+    /// loaded.
+    fn lookup(&self, load_address: u64, address: u64) -> Option<String> {
+        let address = (address.checked_sub(load_address)?).checked_add(self.text_vmaddr)?;
+        let i = self.symbols.partition_point(|(v, _)| *v <= address);
+        let (start, name) = self.symbols.get(i.checked_sub(1)?)?;
+        Some(format!("{name} + {:#x}", address - start))
+    }
+}
+
+/// Whether `v` is fine as part of a file name.
+fn is_valid_part(v: &str) -> bool {
+    !v.is_empty()
+        && !v.starts_with('.')
+        && v.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'))
+}
+
+/// Symbol tables uploaded through the admin API, kept in `symbols/` next to
+/// the config as `name(version)`, the way panics list kexts.
+#[derive(Debug)]
+pub struct Symbols {
+    dir: PathBuf,
+    tables: RwLock<HashMap<(String, String), Arc<SymbolTable>>>,
+}
+
+impl Symbols {
+    pub fn load() -> Self {
+        let dir = PathBuf::from("./symbols");
+        let mut tables = HashMap::new();
+        for ent in std::fs::read_dir(&dir).into_iter().flatten() {
+            let path = ent.unwrap().path();
+            let Some((name, version)) = path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.strip_suffix(')')?.split_once('('))
+            else {
+                continue;
+            };
+            let table = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|v| SymbolTable::parse(&v).map_err(str::to_owned));
+            match table {
+                Ok(v) => {
+                    tables.insert((name.to_owned(), version.to_owned()), Arc::new(v));
+                }
+                Err(e) => println!("Skipping symbols {}: {e}", path.display()),
+            }
+        }
+        Self {
+            dir,
+            tables: RwLock::new(tables),
+        }
+    }
+
+    /// Stores the symbols of `name` at `version`, replacing any it already had.
+    pub fn add(&self, name: String, version: String, binary: &[u8]) -> std::io::Result<()> {
+        if !is_valid_part(&name) || !is_valid_part(&version) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid kext name or version",
+            ));
+        }
+        let table = SymbolTable::parse(binary)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.dir.join(format!("{name}({version})")), binary)?;
+        self.tables
+            .write()
+            .unwrap()
+            .insert((name, version), Arc::new(table));
+        Ok(())
+    }
+
+    /// Removes the symbols of `name` at `version`.
+    pub fn remove(&self, name: &str, version: &str) -> std::io::Result<bool> {
+        let key = (name.to_owned(), version.to_owned());
+        if self.tables.write().unwrap().remove(&key).is_none() {
+            return Ok(false);
+        }
+        match std::fs::remove_file(self.dir.join(format!("{name}({version})"))) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(true),
+        }
+    }
+
+    fn table(&self, kext: &Kext) -> Option<Arc<SymbolTable>> {
+        let table = self
+            .tables
+            .read()
+            .unwrap()
+            .get(&(kext.name.clone(), kext.version.clone()))
+            .cloned()?;
+        // A rebuild with the same version has different addresses.
+        let uuid = kext.uuid.as_deref().and_then(|v| Uuid::parse_str(v).ok());
+        match (uuid, table.uuid) {
+            (Some(a), Some(b)) if a != b => None,
+            _ => Some(table),
+        }
+    }
+
+    /// Resolves backtrace frames inside kexts that have symbols, clearing
+    /// those that no longer do.
+    pub fn symbolicate(&self, panics: &mut [PanicReport]) {
+        for report in panics {
+            let tables: Vec<_> = report
+                .kexts
+                .iter()
+                .filter_map(|kext| Some((kext.start?, kext.end?, self.table(kext)?)))
+                .collect();
+            for frame in &mut report.backtrace {
+                let addr = frame.return_address;
+                frame.resolved = tables
+                    .iter()
+                    .find(|(start, end, _)| (*start..=*end).contains(&addr))
+                    .and_then(|(start, _, table)| table.lookup(*start, addr));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT_VMADDR: u64 = 0x1000;
+
+    fn push_u32(v: &mut Vec<u8>, x: u32) {
+        v.extend(x.to_le_bytes());
+    }
+
+    /// A minimal `x86_64` kext with `__TEXT`, a UUID and two symbols.
+    fn macho() -> Vec<u8> {
+        let strings = b"\0_start\0_panic\0";
+        let header_len = 32;
+        let cmds_len = 72 + 24 + 24;
+        let symoff = header_len + cmds_len;
+        let stroff = symoff + 2 * 16;
+
+        let mut v = Vec::new();
+        for x in [MH_MAGIC_64, CPU_TYPE_X86_64, 3, 0xB, 3, cmds_len, 0, 0] {
+            push_u32(&mut v, x);
+        }
+        push_u32(&mut v, LC_SEGMENT_64);
+        push_u32(&mut v, 72);
+        v.extend(b"__TEXT\0\0\0\0\0\0\0\0\0\0");
+        v.extend(TEXT_VMADDR.to_le_bytes());
+        v.resize(header_len as usize + 72, 0);
+        push_u32(&mut v, LC_UUID);
+        push_u32(&mut v, 24);
+        v.extend(Uuid::from_u128(0x1234).as_bytes());
+        push_u32(&mut v, LC_SYMTAB);
+        push_u32(&mut v, 24);
+        for x in [symoff, 2, stroff, u32::try_from(strings.len()).unwrap()] {
+            push_u32(&mut v, x);
+        }
+        for (strx, value) in [(1, TEXT_VMADDR + 0x10), (8, TEXT_VMADDR + 0x80)] {
+            push_u32(&mut v, strx);
+            v.extend([N_SECT | 1, 1, 0, 0]);
+            v.extend(u64::to_le_bytes(value));
+        }
+        v.extend(strings);
+        v
+    }
+
+    #[test]
+    fn parses_symbols() {
+        let table = SymbolTable::parse(&macho()).unwrap();
+        assert_eq!(table.uuid, Some(Uuid::from_u128(0x1234)));
+        assert_eq!(
+            table.lookup(0x5000, 0x5014).as_deref(),
+            Some("_start + 0x4")
+        );
+        assert_eq!(
+            table.lookup(0x5000, 0x5090).as_deref(),
+            Some("_panic + 0x10")
+        );
+        assert_eq!(table.lookup(0x5000, 0x5008), None);
+        assert_eq!(table.lookup(0x5000, 0x4000), None);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = macho();
+        for len in 0..data.len() {
+            assert!(
+                SymbolTable::parse(&data[..len]).is_err(),
+                "parsed {len} of {} bytes",
+                data.len()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert_eq!(
+            SymbolTable::parse(&[0; 64]).unwrap_err(),
+            "not a Mach-O file"
+        );
+
+        let mut data = macho();
+        data[..4].copy_from_slice(&MH_MAGIC.to_le_bytes());
+        assert!(SymbolTable::parse(&data).is_err());
+
+        let mut data = macho();
+        data[4..8].copy_from_slice(&7_u32.to_le_bytes());
+        assert!(SymbolTable::parse(&data).is_err());
+
+        // A load command too small to move past.
+        let mut data = macho();
+        data[36..40].copy_from_slice(&0_u32.to_le_bytes());
+        assert_eq!(
+            SymbolTable::parse(&data).unwrap_err(),
+            "invalid load command"
+        );
+
+        // A symbol table pointing past the end.
+        let mut data = macho();
+        let symtab = 32 + 72 + 24;
+        data[symtab + 12..symtab + 16].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SymbolTable::parse(&data).is_err());
+    }
+
+    #[test]
+    fn reads_universal_binaries() {
+        let slice = macho();
+        let fat = |cpu: u32, size: usize| {
+            let mut v = Vec::new();
+            for x in [FAT_MAGIC, 1, cpu, 3, 28, u32::try_from(size).unwrap(), 0] {
+                v.extend(x.to_be_bytes());
+            }
+            v.extend(&slice);
+            v
+        };
+        assert!(SymbolTable::parse(&fat(CPU_TYPE_X86_64, slice.len())).is_ok());
+        assert!(SymbolTable::parse(&fat(0x0100_000C, slice.len())).is_err());
+        assert_eq!(
+            SymbolTable::parse(&fat(CPU_TYPE_X86_64, slice.len() + 1)).unwrap_err(),
+            TRUNCATED
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Parsing of RFC 5424 and RFC 3164 syslog messages.
+
+pub const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub facility: u8,
+    pub severity: u8,
+    pub hostname: Option<&'a str>,
+    pub app_name: Option<&'a str>,
+    pub msg: &'a str,
+}
+
+fn nil(v: &str) -> Option<&str> {
+    (!v.is_empty() && v != "-").then_some(v)
+}
+
+/// Length of the structured data at the start of `v`, either the nil value or
+/// a run of `[...]` elements.
+fn structured_data_len(v: &str) -> Option<usize> {
+    if v.starts_with('-') {
+        return Some(1);
+    }
+    let mut in_element = false;
+    let mut in_quotes = false;
+    let mut escaped = false;
+    for (i, c) in v.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' if in_element => in_quotes = !in_quotes,
+            '[' if !in_element => in_element = true,
+            ']' if in_element && !in_quotes => in_element = false,
+            _ if !in_element => return (i != 0).then_some(i),
+            _ => {}
+        }
+    }
+    (!in_element && !v.is_empty()).then_some(v.len())
+}
+
+fn parse_5424(v: &str) -> Option<(Option<&str>, Option<&str>, &str)> {
+    let mut it = v.splitn(6, ' ');
+    let _timestamp = it.next()?;
+    let hostname = nil(it.next()?);
+    let app_name = nil(it.next()?);
+    let _procid = it.next()?;
+    let _msgid = it.next()?;
+    let rest = it.next().unwrap_or_default();
+    let msg = &rest[structured_data_len(rest).unwrap_or_default()..];
+    let msg = msg.strip_prefix(' ').unwrap_or(msg);
+    Some((
+        hostname,
+        app_name,
+        msg.strip_prefix('\u{FEFF}').unwrap_or(msg),
+    ))
+}
+
+/// Strips a `Mmm dd hh:mm:ss ` timestamp.
+fn strip_3164_timestamp(v: &str) -> Option<&str> {
+    let b = v.as_bytes();
+    (b.len() > 16
+        && b[..3].iter().all(u8::is_ascii_alphabetic)
+        && b[3] == b' '
+        && b[6] == b' '
+        && b[9] == b':'
+        && b[12] == b':'
+        && b[15] == b' ')
+        .then(|| &v[16..])
+}
+
+fn parse_3164(v: &str) -> (Option<&str>, Option<&str>, &str) {
+    let (hostname, rest) = match strip_3164_timestamp(v).and_then(|v| v.split_once(' ')) {
+        Some((hostname, rest)) => (nil(hostname), rest),
+        None => (None, v),
+    };
+    let tag_len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || "-_./".contains(c)))
+        .unwrap_or(rest.len());
+    let after = &rest[tag_len..];
+    let msg = if after.starts_with('[') {
+        after.split_once("]:").map(|v| v.1)
+    } else {
+        after.strip_prefix(':')
+    };
+    match msg {
+        Some(msg) if tag_len > 0 => (
+            hostname,
+            Some(&rest[..tag_len]),
+            msg.strip_prefix(' ').unwrap_or(msg),
+        ),
+        _ => (hostname, None, rest),
+    }
+}
+
+/// Parses a syslog message, returning `None` if it has no valid priority.
+pub fn parse(data: &str) -> Option<Message<'_>> {
+    let (pri, rest) = data.strip_prefix('<')?.split_once('>')?;
+    if pri.is_empty() || pri.len() > 3 {
+        return None;
+    }
+    let pri: u8 = pri.parse().ok().filter(|&v| v < 192)?;
+    let rest = rest.trim_end_matches(['\r', '\n', '\0']);
+    let (hostname, app_name, msg) = match rest.strip_prefix("1 ") {
+        Some(v) => parse_5424(v)?,
+        None => parse_3164(rest),
+    };
+    Some(Message {
+        facility: pri / 8,
+        severity: pri % 8,
+        hostname,
+        app_name,
+        msg,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_5424() {
+        let msg = parse(
+            "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+             [exampleSDID@32473 iut=\"3\" eventSource=\"Application\"] \u{FEFF}An event\n",
+        )
+        .unwrap();
+        assert_eq!(
+            msg,
+            Message {
+                facility: 20,
+                severity: 5,
+                hostname: Some("mymachine.example.com"),
+                app_name: Some("evntslog"),
+                msg: "An event",
+            }
+        );
+
+        let msg = parse("<14>1 - - - - - - hello\r\n\0").unwrap();
+        assert_eq!((msg.hostname, msg.app_name, msg.msg), (None, None, "hello"));
+
+        // Brackets inside quoted values don't end the structured data.
+        let msg = parse(r#"<14>1 - host app - - [id k="a\]b" x="]"] after"#).unwrap();
+        assert_eq!(msg.msg, "after");
+    }
+
+    #[test]
+    fn parses_3164() {
+        let msg = parse("<34>Oct 11 22:14:15 mymachine su: 'su root' failed").unwrap();
+        assert_eq!(
+            msg,
+            Message {
+                facility: 4,
+                severity: 2,
+                hostname: Some("mymachine"),
+                app_name: Some("su"),
+                msg: "'su root' failed",
+            }
+        );
+
+        let msg = parse("<13>Feb  5 17:32:18 10.0.0.99 kernel[0]: WhateverRed: hi").unwrap();
+        assert_eq!(
+            (msg.hostname, msg.app_name, msg.msg),
+            (Some("10.0.0.99"), Some("kernel"), "WhateverRed: hi")
+        );
+
+        // Without a timestamp or tag, everything after the priority is the message.
+        let msg = parse("<13>just some text").unwrap();
+        assert_eq!(
+            (msg.hostname, msg.app_name, msg.msg),
+            (None, None, "just some text")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for v in [
+            "no priority",
+            "<>empty",
+            "<13 unclosed",
+            "<abc>text",
+            "<1000>text",
+            "<192>text",
+            "<14>1 too short",
+        ] {
+            assert_eq!(parse(v), None, "{v}");
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Line templates, for telling which messages are new in a log.
+//!
+//! Lines are split into tokens with the variable parts masked, and the server
+//! clusters them Drain-style, so lines differing only in a few tokens share a
+//! template:
+//!
+//! ```text
+//! rad: powering up ASIC at 0xffffff80a1b2c3d4 (try 2)
+//! rad: powering up ASIC at <*> (try <*>)
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+/// Token standing for any value.
+pub const WILDCARD: &str = "<*>";
+
+/// Replaces runs of letters and digits in `text` for which `is_variable` holds
+/// with wildcards.
+pub(crate) fn mask_words(text: &str, is_variable: impl Fn(&str) -> bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        let (word, after) = rest.split_at(end);
+        if !word.is_empty() && is_variable(word) {
+            out.push_str(WILDCARD);
+        } else {
+            out.push_str(word);
+        }
+        let mut chars = after.chars();
+        out.extend(chars.next());
+        rest = chars.as_str();
+    }
+    out
+}
+
+/// Splits a line into tokens, leaving out any kernel timestamp and masking
+/// runs of letters and digits containing a digit, such as numbers, hex
+/// addresses and pointers.
+#[must_use]
+pub fn tokens(line: &str) -> Vec<String> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let line = crate::line::strip_kernel_time(line).map_or(line, |(_, v)| v);
+    mask_words(line, |v| v.contains(|c: char| c.is_ascii_digit()))
+        .split_whitespace()
+        .map(str::to_owned)
+        .collect()
+}
+
+/// How common a template of an entry is.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateStat {
+    pub template: String,
+    /// Lines of the entry with the template.
+    pub count: usize,
+    /// Lines of all entries with the template.
+    pub total: usize,
+    /// Entries with the template, including this one.
+    pub entries: usize,
+    /// Offsets of the entry's lines with the template.
+    pub offsets: Vec<usize>,
+}
+
+impl TemplateStat {
+    /// Whether no other entry has the template.
+    #[must_use]
+    pub const fn is_new(&self) -> bool {
+        self.entries <= 1
+    }
+}
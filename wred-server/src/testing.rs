@@ -0,0 +1,54 @@
+//! Helpers shared by the tests.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use wred_server::{LogEntry, Outcome};
+
+/// An entry from a legacy sender holding `data`, with nothing worked out.
+pub fn entry(data: &[u8]) -> LogEntry {
+    LogEntry {
+        last_updated: 1,
+        addr: "10.0.0.2:5000".parse().unwrap(),
+        device_id: None,
+        device: None,
+        session: None,
+        is_ended: false,
+        is_closed: false,
+        metadata: BTreeMap::new(),
+        data: data.to_vec(),
+        segments: Vec::new(),
+        panics: Vec::new(),
+        outcome: Outcome::Unknown,
+        labels: BTreeMap::new(),
+    }
+}
+
+/// A fresh directory, removed again when dropped.
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("wred-{}-{name}", std::process::id()));
+        let _e = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _e = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// Seconds between checks for a changed certificate or key, if any.
+    #[serde(default)]
+    pub reload_secs: Option<u64>,
+    /// Whether the logger port requires TLS too, which raw senders can't do.
+    #[serde(default)]
+    pub logger: bool,
+}
+
+fn load_key(config: &TlsConfig) -> std::io::Result<CertifiedKey> {
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        &config.cert,
+    )?))
+    .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid("no certificates found".to_owned()));
+    }
+    let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(
+        &config.key,
+    )?))?
+    .ok_or_else(|| invalid("no private key found".to_owned()))?;
+    let key =
+        rustls::crypto::ring::sign::any_supported_type(&key).map_err(|e| invalid(e.to_string()))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|v| v.modified()).ok()
+}
+
+/// Serves the current certificate, which can be swapped while running.
+#[derive(Debug)]
+struct Resolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.0.read().unwrap()))
+    }
+}
+
+/// Loads the certificate and key, and if configured, starts reloading them
+/// when they change.
+pub fn server_config(config: &TlsConfig) -> std::io::Result<Arc<rustls::ServerConfig>> {
+    let resolver = Arc::new(Resolver(RwLock::new(Arc::new(load_key(config)?))));
+    if let Some(secs) = config.reload_secs {
+        let resolver = Arc::clone(&resolver);
+        let config = config.clone();
+        tokio::spawn(async move {
+            let mut last = (modified(&config.cert), modified(&config.key));
+            let mut interval = tokio::time::interval(Duration::from_secs(secs.max(1)));
+            loop {
+                interval.tick().await;
+                let current = (modified(&config.cert), modified(&config.key));
+                if current == last {
+                    continue;
+                }
+                last = current;
+                // Possibly only one of the files was written yet, in which case
+                // this is retried once the other one changes.
+                match load_key(&config) {
+                    Ok(key) => {
+                        *resolver.0.write().unwrap() = Arc::new(key);
+                        println!("Reloaded TLS certificate");
+                    }
+                    Err(e) => println!("Failed reloading TLS certificate: {e}"),
+                }
+            }
+        });
+    }
+    rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+        .map(|v| {
+            Arc::new(
+                v.with_no_client_auth()
+                    .with_cert_resolver(resolver as Arc<dyn ResolvesServerCert>),
+            )
+        })
+}
@@ -0,0 +1,110 @@
+use std::{path::PathBuf, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceToken {
+    pub name: String,
+    pub token: String,
+}
+
+/// Why a sender was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    MissingToken,
+    UnknownToken,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingToken => write!(f, "no ingest token"),
+            Self::UnknownToken => write!(f, "unknown ingest token"),
+        }
+    }
+}
+
+/// Ingest tokens from the config and those created through the admin API,
+/// which are kept in `tokens.ron` next to the config.
+#[derive(Debug)]
+pub struct Tokens {
+    path: PathBuf,
+    strict: bool,
+    configured: Vec<DeviceToken>,
+    created: RwLock<Vec<DeviceToken>>,
+}
+
+impl Tokens {
+    pub fn load(config: &crate::state::ServerConfig) -> Self {
+        let path = PathBuf::from("./tokens.ron");
+        let created = std::fs::File::open(&path)
+            .map(|f| ron::de::from_reader(f).expect("Failed reading tokens"))
+            .unwrap_or_default();
+        Self {
+            path,
+            strict: config.strict_tokens,
+            configured: config.devices.clone(),
+            created: RwLock::new(created),
+        }
+    }
+
+    pub const fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn device(&self, token: &str) -> Option<String> {
+        let created = self.created.read().unwrap();
+        self.configured
+            .iter()
+            .chain(created.iter())
+            .find(|v| v.token == token)
+            .map(|v| v.name.clone())
+    }
+
+    /// Resolves the device name for a sender presenting `token`.
+    ///
+    /// Unknown or missing tokens are only refused in strict mode.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Option<String>, Rejected> {
+        match token.map(|v| self.device(v)) {
+            Some(Some(device)) => Ok(Some(device)),
+            Some(None) if self.strict => Err(Rejected::UnknownToken),
+            None if self.strict => Err(Rejected::MissingToken),
+            _ => Ok(None),
+        }
+    }
+
+    fn persist(&self, created: &[DeviceToken]) -> std::io::Result<()> {
+        let data = ron::ser::to_string_pretty(created, ron::ser::PrettyConfig::default())
+            .map_err(std::io::Error::other)?;
+        std::fs::write(&self.path, data)
+    }
+
+    /// Creates a token for the device `name`, replacing any it already had.
+    pub fn create(&self, name: String) -> std::io::Result<String> {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let mut created = self.created.write().unwrap();
+        let mut tokens: Vec<_> = created.iter().filter(|v| v.name != name).cloned().collect();
+        tokens.push(DeviceToken {
+            name,
+            token: token.clone(),
+        });
+        // Written under the lock so concurrent changes are saved in order, and
+        // before the token is accepted so it's never valid without being saved.
+        self.persist(&tokens)?;
+        *created = tokens;
+        Ok(token)
+    }
+
+    /// Revokes the created token of the device `name`.
+    pub fn revoke(&self, name: &str) -> std::io::Result<bool> {
+        let mut created = self.created.write().unwrap();
+        let len = created.len();
+        created.retain(|v| v.name != name);
+        if created.len() == len {
+            return Ok(false);
+        }
+        let res = self.persist(&created);
+        drop(created);
+        res.map(|()| true)
+    }
+}